@@ -51,17 +51,15 @@ fn file_reader_fut(
     tx: &UnboundedSender<usize>,
 ) -> impl Future<Output = ()> + 'static {
     let tx_clone = tx.clone();
-    let start = created_at.clone();
+    let start = *created_at;
 
     async move {
         assert!(
             OWNER_ID
                 .compare_exchange(0, id, Ordering::SeqCst, Ordering::SeqCst)
                 .is_ok(),
-            format!(
-                "{}'s exclusive access is violated ... which should not happen to this test ...",
-                id
-            )
+            "{}'s exclusive access is violated ... which should not happen to this test ...",
+            id
         );
 
         // since we own the access, take a nap and see if anyone can invade our space ...
@@ -0,0 +1,148 @@
+use crate::timer;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// The callback to call once the clock reaches the moment it's scheduled for.
+pub type Alarm = Box<dyn FnOnce() + Send>;
+
+/// The source of time for a gatekeeper: all the time-based flavors, timeouts, deadlines and queue
+/// disciplines read the time from the clock of their gatekeeper, and wait on it.
+pub trait Clock: Send + Sync {
+    /// The time elapsed since the clock started.
+    fn now(&self) -> Duration;
+
+    /// Call the alarm once the clock has reached `at`. The alarm shall not be called by the caller
+    /// of `schedule`, as the caller may be holding on to the state the alarm is about to touch.
+    fn schedule(&self, at: Duration, alarm: Alarm);
+}
+
+/// The clock following the system time, whose alarms are served by a single timer thread shared
+/// by all the gatekeepers. This is the clock of a gatekeeper unless told otherwise.
+pub struct SystemClock {
+    origin: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        SystemClock {
+            origin: Instant::now(),
+        }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        SystemClock::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.origin.elapsed()
+    }
+
+    fn schedule(&self, at: Duration, alarm: Alarm) {
+        timer::schedule(self.origin + at, alarm);
+    }
+}
+
+struct MockState {
+    now: Duration,
+    alarms: Vec<(Duration, Alarm)>,
+}
+
+/// A clock that only moves when told to, such that the time-based behaviors can be tested
+/// deterministically: the alarms go off, in the order of their moments, as the clock is advanced
+/// past them. Nothing waiting on the clock (e.g. the refills, timeouts, or the backoff of a
+/// cooperative future) happens until then.
+///
+/// ```
+/// use futures_rate::{GateKeeper, MockClock, RatioType};
+/// use std::sync::Arc;
+/// use std::time::Duration;
+///
+/// let clock = Arc::new(MockClock::new());
+/// let gatekeeper = GateKeeper::with_clock(
+///     RatioType::SlidingWindowLog(5, Duration::from_secs(1)),
+///     clock.clone(),
+/// );
+///
+/// let admitted = (0..10)
+///     .filter_map(|i| gatekeeper.try_issue(async move { i }).ok())
+///     .count();
+/// assert_eq!(admitted, 5);
+///
+/// clock.advance(Duration::from_secs(1));
+/// assert!(gatekeeper.try_issue(async {}).is_ok());
+/// ```
+pub struct MockClock {
+    state: Mutex<MockState>,
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        MockClock {
+            state: Mutex::new(MockState {
+                now: Duration::from_secs(0),
+                alarms: Vec::new(),
+            }),
+        }
+    }
+
+    /// Move the clock forward, and call the alarms that are due by then.
+    pub fn advance(&self, by: Duration) {
+        let to = self.state().now + by;
+
+        // the alarms may schedule new ones that are due by then as well.
+        while let Some((at, alarm)) = self.next_due(to) {
+            let mut state = self.state();
+            state.now = state.now.max(at);
+            drop(state);
+
+            alarm();
+        }
+
+        self.state().now = to;
+    }
+
+    /// The number of alarms yet to go off.
+    pub fn pending(&self) -> usize {
+        self.state().alarms.len()
+    }
+
+    fn next_due(&self, to: Duration) -> Option<(Duration, Alarm)> {
+        let mut state = self.state();
+
+        // take the earliest one, and the one scheduled first among them.
+        let pos = state
+            .alarms
+            .iter()
+            .enumerate()
+            .filter(|(_, (at, _))| *at <= to)
+            .min_by_key(|(_, (at, _))| *at)
+            .map(|(pos, _)| pos)?;
+
+        Some(state.alarms.remove(pos))
+    }
+
+    fn state(&self) -> MutexGuard<'_, MockState> {
+        self.state.lock().expect("the mock clock is corrupted ...")
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        MockClock::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Duration {
+        self.state().now
+    }
+
+    /// The alarms that are already due go off on the next advance, even if it's by zero.
+    fn schedule(&self, at: Duration, alarm: Alarm) {
+        self.state().alarms.push((at, alarm));
+    }
+}
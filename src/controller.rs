@@ -1,4 +1,269 @@
+use crate::clock::Clock;
+use crate::InterruptedReason;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
 
+const WAITING: usize = 0;
+const ADMITTED: usize = 1;
+const CANCELLED: usize = 2;
+const TIMED_OUT: usize = 3;
+const EXPIRED: usize = 4;
+const EVICTED: usize = 5;
+const SHED: usize = 6;
+
+/// The state shared between the controller and the future it controls.
+pub(crate) struct Signal {
+    state: AtomicUsize,
+    waker: Mutex<Option<Waker>>,
+    timeout: Option<Duration>,
+    deadline: Option<Duration>,
+    armed: AtomicBool,
+    clock: Option<Arc<dyn Clock>>,
+}
+
+impl Signal {
+    pub(crate) fn new() -> Self {
+        Signal {
+            state: AtomicUsize::new(WAITING),
+            waker: Mutex::new(None),
+            timeout: None,
+            deadline: None,
+            armed: AtomicBool::new(false),
+            clock: None,
+        }
+    }
+
+    /// The future shall give up waiting for the token once the timeout is up by the clock.
+    pub(crate) fn with_timeout(timeout: Duration, clock: Arc<dyn Clock>) -> Self {
+        Signal {
+            timeout: Some(timeout),
+            clock: Some(clock),
+            ..Signal::new()
+        }
+    }
+
+    /// The future shall be rejected if it's only admitted after the deadline by the clock.
+    pub(crate) fn with_deadline(deadline: Duration, clock: Arc<dyn Clock>) -> Self {
+        Signal {
+            deadline: Some(deadline),
+            clock: Some(clock),
+            ..Signal::new()
+        }
+    }
+
+    /// Register the waker of the controlled future, such that it will be woken up once
+    /// interrupted, and start the clock for the timeout if we haven't.
+    pub(crate) fn register(self: &Arc<Self>, waker: &Waker) {
+        let mut slot = self.waker.lock().expect("the signal is corrupted ...");
+
+        if !slot.as_ref().is_some_and(|w| w.will_wake(waker)) {
+            slot.replace(waker.clone());
+        }
+
+        drop(slot);
+
+        if let (Some(timeout), Some(clock)) = (self.timeout, self.clock.as_ref()) {
+            if !self.armed.swap(true, Ordering::AcqRel) {
+                let this = Arc::downgrade(self);
+
+                clock.schedule(
+                    clock.now() + timeout,
+                    Box::new(move || {
+                        if let Some(signal) = this.upgrade() {
+                            signal.time_out();
+                        }
+                    }),
+                );
+            }
+        }
+    }
+
+    /// Mark the future as admitted, which stops the clock for the timeout. Return false if the
+    /// future has been interrupted before it could be admitted.
+    pub(crate) fn admit(&self) -> bool {
+        self.expire();
+
+        match self
+            .state
+            .compare_exchange(WAITING, ADMITTED, Ordering::AcqRel, Ordering::Acquire)
+        {
+            Ok(_) => true,
+            Err(state) => state == ADMITTED,
+        }
+    }
+
+    pub(crate) fn interrupted(&self) -> Option<InterruptedReason> {
+        self.expire();
+
+        match self.state.load(Ordering::Acquire) {
+            CANCELLED => Some(InterruptedReason::Cancelled),
+            TIMED_OUT => Some(InterruptedReason::TimedOut),
+            EXPIRED => Some(InterruptedReason::DeadlineExceeded),
+            EVICTED => Some(InterruptedReason::QueueFull),
+            SHED => Some(InterruptedReason::Overloaded),
+            _ => None,
+        }
+    }
+
+    /// Check if the future has been rejected, since the deadline has passed while it's still
+    /// waiting for the token.
+    pub(crate) fn is_expired(&self) -> bool {
+        self.expire();
+        self.state.load(Ordering::Acquire) == EXPIRED
+    }
+
+    #[inline]
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.state.load(Ordering::Acquire) == CANCELLED
+    }
+
+    /// The timeout is up, interrupt the future if it's still waiting for the token.
+    pub(crate) fn time_out(&self) {
+        if self
+            .state
+            .compare_exchange(WAITING, TIMED_OUT, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+        {
+            self.wake();
+        }
+    }
+
+    /// Evict the future from the line to make room for the others, and return false if it's no
+    /// longer waiting in line for the token.
+    pub(crate) fn evict(&self) -> bool {
+        self.drop_out(EVICTED)
+    }
+
+    /// Shed the future from the overloaded line, and return false if it's no longer waiting in
+    /// line for the token.
+    pub(crate) fn shed(&self) -> bool {
+        self.drop_out(SHED)
+    }
+
+    fn drop_out(&self, state: usize) -> bool {
+        if self
+            .state
+            .compare_exchange(WAITING, state, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            return false;
+        }
+
+        self.wake();
+        true
+    }
+
+    /// Reject the future if the deadline has passed while it's still waiting for the token.
+    fn expire(&self) {
+        let now = self.clock.as_ref().map(|clock| clock.now());
+
+        if self.deadline.zip(now).is_some_and(|(deadline, now)| now >= deadline) {
+            let _ = self.state.compare_exchange(
+                WAITING,
+                EXPIRED,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            );
+        }
+    }
+
+    fn cancel(&self) {
+        let prev = self
+            .state
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |state| {
+                if state == WAITING || state == ADMITTED {
+                    Some(CANCELLED)
+                } else {
+                    None
+                }
+            });
+
+        if prev.is_ok() {
+            self.wake();
+        }
+    }
+
+    fn wake(&self) {
+        let waker = self
+            .waker
+            .lock()
+            .expect("the signal is corrupted ...")
+            .take();
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+/// The handle to interrupt the future issued by `GateKeeper::issue_interruptable`.
+#[derive(Clone)]
 pub struct Controller {
+    signal: Arc<Signal>,
+}
+
+impl Controller {
+    pub(crate) fn new(signal: Arc<Signal>) -> Self {
+        Controller { signal }
+    }
+
+    /// Cancel the future: if it's still waiting in line for a token, it leaves the line and
+    /// resolves to `Err(InterruptedReason::Cancelled)` right away; if it's already running, it's
+    /// dropped and the token is returned.
+    pub fn cancel(&self) {
+        self.signal.cancel();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.signal.is_cancelled()
+    }
+}
+
+/// The running future that can be dropped halfway once cancelled.
+pub(crate) struct Interruptable<F>
+where
+    F: Future,
+{
+    fut: Option<Pin<Box<F>>>,
+    signal: Arc<Signal>,
+}
+
+impl<F> Interruptable<F>
+where
+    F: Future,
+{
+    pub(crate) fn new(fut: F, signal: Arc<Signal>) -> Self {
+        Interruptable {
+            fut: Some(Box::pin(fut)),
+            signal,
+        }
+    }
+}
+
+impl<F> Future for Interruptable<F>
+where
+    F: Future,
+{
+    type Output = Result<F::Output, InterruptedReason>;
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+
+        // register before checking, such that a cancellation in between won't be missed.
+        this.signal.register(ctx.waker());
+
+        if this.signal.is_cancelled() {
+            this.fut.take();
+            return Poll::Ready(Err(InterruptedReason::Cancelled));
+        }
 
+        match this.fut.as_mut() {
+            Some(fut) => fut.as_mut().poll(ctx).map(Ok),
+            None => Poll::Ready(Err(InterruptedReason::Cancelled)),
+        }
+    }
 }
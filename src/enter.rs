@@ -3,7 +3,7 @@
 use std::cell::Cell;
 
 thread_local!(
-    static ENTERED: Cell<usize> = Cell::new(0)
+    static ENTERED: Cell<usize> = const { Cell::new(0) }
 );
 
 pub(crate) fn arrive() {
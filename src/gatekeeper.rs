@@ -1,17 +1,22 @@
 #![allow(deprecated)]
 
-use crate::controller::Controller;
+use crate::clock::{Clock, SystemClock};
+use crate::controller::{Controller, Interruptable, Signal};
 use crate::inner::{InnerPool, TokenFetcher};
-use crate::pass::{Envelope, Permit, Ticket};
-use crate::{enter, InterruptedReason, RatioType, TokenPolicy, SpinPolicy};
+use crate::pass::{Envelope, Permit, Ticket, TicketStub};
+use crate::threads_queue::Rank;
+use crate::{
+    enter, InterruptedReason, OverflowPolicy, QueueDiscipline, RatioType, SpinPolicy, TokenPolicy,
+};
 use std::future::Future;
-use std::sync::{Arc, Weak};
-use std::thread;
-use std::time::Duration;
-
-struct KeeperPolicy {
-    token: TokenPolicy,
-    spin: SpinPolicy,
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub(crate) struct KeeperPolicy {
+    pub(crate) token: TokenPolicy,
+    pub(crate) spin: SpinPolicy,
+    pub(crate) max_waiters: usize,
+    pub(crate) overflow: OverflowPolicy,
 }
 
 impl Default for KeeperPolicy {
@@ -19,6 +24,8 @@ impl Default for KeeperPolicy {
         KeeperPolicy {
             token: TokenPolicy::Preemptive,
             spin: SpinPolicy::InplaceWait,
+            max_waiters: usize::MAX,
+            overflow: OverflowPolicy::Reject,
         }
     }
 }
@@ -32,28 +39,120 @@ impl GateKeeper {
     pub fn new(size: usize) -> Self {
         assert!(size > 0);
 
+        Self::build(RatioType::Static(size))
+    }
+
+    pub fn with_rate(count_per_interval: usize, interval: Duration) -> Self {
+        assert!(count_per_interval > 0);
+
+        Self::build(RatioType::FixedRate(count_per_interval, interval))
+    }
+
+    pub fn with_token_bucket(capacity: usize, refill: usize, per: Duration) -> Self {
+        assert!(capacity > 0 && refill > 0);
+        assert!(per > Duration::from_nanos(0));
+
+        Self::build(RatioType::TokenBucket {
+            capacity,
+            refill,
+            per,
+        })
+    }
+
+    pub fn with_gcra(rate: usize, per: Duration, burst: usize) -> Self {
+        assert!(rate > 0 && burst > 0);
+        assert!(per > Duration::from_nanos(0));
+
+        Self::build(RatioType::Gcra { rate, per, burst })
+    }
+
+    pub fn with_sliding_log(count: usize, window: Duration) -> Self {
+        assert!(count > 0);
+        assert!(window > Duration::from_nanos(0));
+
+        Self::build(RatioType::SlidingWindowLog(count, window))
+    }
+
+    pub fn with_sliding_counter(count: usize, window: Duration) -> Self {
+        assert!(count > 0);
+        assert!(window > Duration::from_nanos(0));
+
+        Self::build(RatioType::SlidingWindowCounter(count, window))
+    }
+
+    pub fn with_pacing(count: usize, per: Duration, max_delay: Option<Duration>) -> Self {
+        assert!(count > 0);
+        assert!(per > Duration::from_nanos(0));
+
+        Self::build(RatioType::Pacing {
+            count,
+            per,
+            max_delay,
+        })
+    }
+
+    pub fn with_capped_rate(in_flight: usize, count: usize, per: Duration) -> Self {
+        assert!(in_flight > 0 && count > 0);
+        assert!(per > Duration::from_nanos(0));
+
+        Self::build(RatioType::Composite {
+            in_flight,
+            count,
+            per,
+        })
+    }
+
+    pub fn with_aimd(initial: usize, min: usize, max: usize, backoff: f64) -> Self {
+        assert!(min > 0 && min <= initial && initial <= max);
+        assert!(backoff > 0. && backoff < 1.);
+
+        Self::build(RatioType::Aimd {
+            initial,
+            min,
+            max,
+            backoff,
+        })
+    }
+
+    pub fn with_gradient(initial: usize, min: usize, max: usize) -> Self {
+        assert!(min > 0 && min <= initial && initial <= max);
+
+        Self::build(RatioType::Gradient { initial, min, max })
+    }
+
+    /// Create a gatekeeper drawing from the budget of this one: a future issued by the child is
+    /// only admitted if the child and all its ancestors can admit it, and the tokens are returned
+    /// to all of them once the future is done.
+    pub fn child(&self, ratio: RatioType) -> Self {
+        assert!(ratio.is_valid());
+
         enter::arrive();
 
         GateKeeper {
-            inner: Arc::new(InnerPool::new(size, RatioType::Static(size))),
+            inner: build_pool(ratio, Some(Arc::clone(&self.inner)), self.inner.clock()),
             policy: Default::default(),
         }
     }
 
-    pub fn with_rate(count_per_interval: usize, interval: Duration) -> Self {
-        assert!(count_per_interval > 0);
+    /// Create a gatekeeper of the given flavor reading the time from the given clock, e.g. from a
+    /// `MockClock` to test the time-based flavors without waiting for the time to pass. The
+    /// children of the gatekeeper share its clock.
+    pub fn with_clock(ratio: RatioType, clock: Arc<dyn Clock>) -> Self {
+        assert!(ratio.is_valid());
 
         enter::arrive();
 
-        let inner_pool = Arc::new(InnerPool::new(
-            count_per_interval,
-            RatioType::FixedRate(count_per_interval, interval),
-        ));
+        GateKeeper {
+            inner: build_pool(ratio, None, clock),
+            policy: Default::default(),
+        }
+    }
 
-        Self::spawn_token_generator(Arc::downgrade(&Arc::clone(&inner_pool)));
+    fn build(ratio: RatioType) -> Self {
+        enter::arrive();
 
         GateKeeper {
-            inner: inner_pool,
+            inner: build_pool(ratio, None, Arc::new(SystemClock::new())),
             policy: Default::default(),
         }
     }
@@ -83,38 +182,164 @@ impl GateKeeper {
         R: Send + 'static,
         F: Future<Output = R> + 'static,
     {
-        if self.is_closed() {
-            return None;
-        }
+        self.issue_weighted(1, fut)
+    }
 
-        let mut fut_wrapper = Some(fut);
+    pub fn issue_weighted<R, F>(&self, cost: usize, fut: F) -> Option<impl Future<Output = R>>
+    where
+        R: Send + 'static,
+        F: Future<Output = R> + 'static,
+    {
+        guard(&self.inner, &self.policy, cost, Rank::default(), fut)
+    }
 
-        let mut ticket: Ticket<R, F> = match self.policy.token {
-            TokenPolicy::Cooperative => Ticket::new(Arc::clone(&self.inner), fut_wrapper.take()),
-            TokenPolicy::Preemptive => Ticket::new(Arc::clone(&self.inner), None),
+    /// Issue the future with the given priority: the freed up tokens go to the waiting future of
+    /// the highest priority first, and the futures of the same priority are served in the order
+    /// they arrived. The futures issued otherwise have the lowest priority, i.e. `0`. While a future
+    /// of a priority above `0` is waiting, the tokens are handed to the line directly even under
+    /// `QueueDiscipline::Relaxed`, such that no newly arriving future can take them first. See
+    /// `GateKeeperConfig::set_priority_aging` to keep the futures of low priorities from waiting
+    /// forever.
+    pub fn issue_with_priority<R, F>(
+        &self,
+        priority: usize,
+        fut: F,
+    ) -> Option<impl Future<Output = R>>
+    where
+        R: Send + 'static,
+        F: Future<Output = R> + 'static,
+    {
+        let rank = Rank {
+            priority,
+            ..Default::default()
         };
 
-        if self.policy.spin != SpinPolicy::InplaceWait {
-            ticket.set_spin_policy(self.policy.spin);
-        }
+        guard(&self.inner, &self.policy, 1, rank, fut)
+    }
 
-        let fut = async move {
-            let _stub = match ticket.await {
-                Ok(Envelope::Stub(t)) => t,
-                Ok(Envelope::Output(val)) => return val,
-                Err(_e) => panic!("issuance of the pass has been disrupted unexpectedly ..."),
-            };
+    /// Issue the future on behalf of the given tenant, such that the tenants waiting for the
+    /// tokens take turns in proportion to their weights, rather than in the order their futures
+    /// arrived: a tenant flooding the gatekeeper only holds up its own futures. The futures issued
+    /// otherwise belong to the tenant `0` with the weight of `1`. While a future issued for a
+    /// tenant is waiting, the tokens are handed to the line directly even under
+    /// `QueueDiscipline::Relaxed`, such that the newly arriving futures can't take them first.
+    pub fn issue_for_tenant<R, F>(
+        &self,
+        tenant: usize,
+        weight: usize,
+        fut: F,
+    ) -> Option<impl Future<Output = R>>
+    where
+        R: Send + 'static,
+        F: Future<Output = R> + 'static,
+    {
+        if weight == 0 {
+            return None;
+        }
 
-            fut_wrapper.take().unwrap().await
+        let rank = Rank {
+            tenant,
+            weight,
+            ..Default::default()
         };
 
-        Some(fut)
+        guard(&self.inner, &self.policy, 1, rank, fut)
+    }
+
+    /// Issue the future only if a token is available right now, otherwise hand the future back,
+    /// such that the caller can fall back to something else instead of waiting.
+    pub fn try_issue<R, F>(&self, fut: F) -> Result<impl Future<Output = R>, F>
+    where
+        R: Send + 'static,
+        F: Future<Output = R> + 'static,
+    {
+        self.try_issue_weighted(1, fut)
+    }
+
+    pub fn try_issue_weighted<R, F>(
+        &self,
+        cost: usize,
+        fut: F,
+    ) -> Result<impl Future<Output = R>, F>
+    where
+        R: Send + 'static,
+        F: Future<Output = R> + 'static,
+    {
+        try_guard(&self.inner, cost, fut)
+    }
+
+    /// Issue a future that reports whether it has succeeded, such that the adaptive flavors can
+    /// learn from the outcome: an `Err` (e.g. a timeout or an error from the backend) cuts the
+    /// in-flight cap, while an `Ok` lets it grow.
+    pub fn issue_fallible<T, E, F>(&self, fut: F) -> Option<impl Future<Output = Result<T, E>>>
+    where
+        T: Send + 'static,
+        E: Send + 'static,
+        F: Future<Output = Result<T, E>> + 'static,
+    {
+        let pool = Arc::clone(&self.inner);
+
+        guard(&self.inner, &self.policy, 1, Rank::default(), async move {
+            let res = fut.await;
+            pool.on_outcome(res.is_ok());
+            res
+        })
     }
 
     pub fn issue_interruptable<R, F>(
         &self,
         fut: F,
     ) -> Option<(impl Future<Output = Result<R, InterruptedReason>>, Controller)>
+    where
+        R: Send + 'static,
+        F: Future<Output = R> + 'static,
+    {
+        let signal = Arc::new(Signal::new());
+        let controller = Controller::new(Arc::clone(&signal));
+
+        self.interruptable(signal, fut).map(|fut| (fut, controller))
+    }
+
+    /// Issue a future that gives up waiting for the token if it can't get one within the given
+    /// timeout, since it's first polled, and resolves to `Err(InterruptedReason::TimedOut)`. A
+    /// future that has been admitted in time will run to its completion.
+    pub fn issue_with_timeout<R, F>(
+        &self,
+        timeout: Duration,
+        fut: F,
+    ) -> Option<impl Future<Output = Result<R, InterruptedReason>>>
+    where
+        R: Send + 'static,
+        F: Future<Output = R> + 'static,
+    {
+        let signal = Signal::with_timeout(timeout, self.inner.clock());
+        self.interruptable(Arc::new(signal), fut)
+    }
+
+    /// Issue a future that's only worth running before the given deadline: if the deadline has
+    /// passed by the time it would be admitted, it's rejected with
+    /// `Err(InterruptedReason::DeadlineExceeded)`, and the token goes to the next one in line. The
+    /// time left till the deadline is measured by the clock of the gatekeeper from now on.
+    pub fn issue_with_deadline<R, F>(
+        &self,
+        deadline: Instant,
+        fut: F,
+    ) -> Option<impl Future<Output = Result<R, InterruptedReason>>>
+    where
+        R: Send + 'static,
+        F: Future<Output = R> + 'static,
+    {
+        let clock = self.inner.clock();
+        let deadline = clock.now() + deadline.saturating_duration_since(Instant::now());
+
+        self.interruptable(Arc::new(Signal::with_deadline(deadline, clock)), fut)
+    }
+
+    fn interruptable<R, F>(
+        &self,
+        signal: Arc<Signal>,
+        fut: F,
+    ) -> Option<impl Future<Output = Result<R, InterruptedReason>>>
     where
         R: Send + 'static,
         F: Future<Output = R> + 'static,
@@ -123,15 +348,25 @@ impl GateKeeper {
             return None;
         }
 
-        let ticket: Ticket<R, F> = Ticket::new(Arc::clone(&self.inner), None);
+        let mut ticket: Ticket<R, F> = Ticket::new(Arc::clone(&self.inner), None);
+        ticket.set_signal(Arc::clone(&signal));
+        ticket.set_max_waiters(self.policy.max_waiters, self.policy.overflow);
 
-        Some((
-            async move {
-                ticket.await?;
-                Ok(fut.await)
-            },
-            Controller {},
-         ))
+        Some(async move {
+            // hold on to the stub till the future is done or dropped.
+            let _stub = match ticket.await? {
+                Envelope::Stub(t) => t,
+                Envelope::Output(val) => return Ok(val),
+            };
+
+            Interruptable::new(fut, signal).await
+        })
+    }
+
+    /// The number of futures waiting in line for the tokens right now. A future only counts once
+    /// however many times it's been polled while waiting.
+    pub fn waiting(&self) -> usize {
+        self.inner.waiting()
     }
 
     pub fn close(&self) {
@@ -142,32 +377,32 @@ impl GateKeeper {
     pub fn is_closed(&self) -> bool {
         self.inner.is_closed()
     }
-
-    fn spawn_token_generator(pool: Weak<InnerPool>) {
-        thread::spawn(move || {
-            loop {
-                if let Some(p) = pool.upgrade() {
-                    // if the pool ref still exists
-                    if let RatioType::FixedRate(count, d) = p.get_flavor() {
-                        // set amount of tokens allowed in this time slab to the pool
-                        p.reset_token(count);
-                        thread::sleep(d);
-
-                        continue;
-                    }
-                }
-
-                // the pool has quit, we shall too.
-                return;
-            }
-        });
-    }
 }
 
 pub trait GateKeeperConfig {
+    /// Switch to the given flavor. Panics if the flavor could never admit a future, e.g. a token
+    /// bucket that's never refilled, just like the constructors of the flavor would.
     fn set_ratio(&mut self, ratio: RatioType);
     fn set_token_policy(&mut self, policy: TokenPolicy);
     fn set_spin_policy(&mut self, spin: SpinPolicy);
+
+    /// Limit the number of futures waiting in line for the tokens, such that the futures are
+    /// turned down (or make room by evicting the oldest ones, depending on the overflow policy)
+    /// once the line is full, instead of piling up. An interruptable future (e.g. one issued by
+    /// `issue_with_timeout`) is checked when it would get in line, and resolves to
+    /// `Err(InterruptedReason::QueueFull)` if there's no room for it; a future issued by `issue`
+    /// can't be turned away once issued, so it takes the tokens upon issuance if they're available
+    /// and no one is waiting for them, or holds a place in line from its issuance till it's
+    /// admitted or in line otherwise, and `issue` returns `None` if there's no place left for it.
+    /// Unbounded by default.
+    fn set_max_waiters(&mut self, _max_waiters: usize, _overflow: OverflowPolicy) {}
+
+    /// Decide in which order the waiting futures are admitted, see `QueueDiscipline`.
+    fn set_queue_discipline(&mut self, _discipline: QueueDiscipline) {}
+
+    /// Raise the priority of a waiting future by one for every `aging` it has waited in line,
+    /// such that the futures of low priorities are served eventually. No aging by default.
+    fn set_priority_aging(&mut self, _aging: Option<Duration>) {}
 }
 
 impl GateKeeperConfig for GateKeeper {
@@ -179,23 +414,21 @@ impl GateKeeperConfig for GateKeeper {
         self.policy.spin = spin;
     }
 
-    fn set_ratio(&mut self, ratio: RatioType) {
-        if self.inner.is_closed() {
-            return;
-        }
+    fn set_max_waiters(&mut self, max_waiters: usize, overflow: OverflowPolicy) {
+        self.policy.max_waiters = max_waiters;
+        self.policy.overflow = overflow;
+    }
 
-        let flavor = self.inner.get_flavor();
-        self.inner.set_flavor(ratio.clone());
+    fn set_queue_discipline(&mut self, discipline: QueueDiscipline) {
+        self.inner.set_discipline(discipline);
+    }
 
-        match (flavor, ratio) {
-            (RatioType::Static(from), RatioType::Static(to)) => {
-                self.inner.static_rebalance(from, to);
-            }
-            (RatioType::Static(_), RatioType::FixedRate(_, _)) => {
-                Self::spawn_token_generator(Arc::downgrade(&Arc::clone(&self.inner)));
-            }
-            _ => {}
-        };
+    fn set_priority_aging(&mut self, aging: Option<Duration>) {
+        self.inner.set_aging(aging);
+    }
+
+    fn set_ratio(&mut self, ratio: RatioType) {
+        apply_ratio(&self.inner, ratio);
     }
 }
 
@@ -205,3 +438,162 @@ impl Drop for GateKeeper {
         self.close();
     }
 }
+
+/// Create the pool for the given flavor, and schedule its refills if the flavor needs them.
+pub(crate) fn build_pool(
+    ratio: RatioType,
+    parent: Option<Arc<InnerPool>>,
+    clock: Arc<dyn Clock>,
+) -> Arc<InnerPool> {
+    let pool = InnerPool::new(ratio.initial_tokens(), ratio, parent, clock);
+
+    if ratio.is_refilled() {
+        pool.start_refill();
+    }
+
+    pool
+}
+
+/// Switch the pool to the given flavor, and settle the tokens already handed out by the previous
+/// flavor.
+pub(crate) fn apply_ratio(pool: &Arc<InnerPool>, ratio: RatioType) {
+    assert!(ratio.is_valid());
+
+    if pool.is_closed() {
+        return;
+    }
+
+    let flavor = pool.get_flavor();
+    pool.set_flavor(ratio);
+
+    match (flavor.in_flight_cap(), ratio.in_flight_cap()) {
+        (Some(_), Some(to)) => pool.resize_in_flight(to),
+        (None, Some(to)) => pool.reset_in_flight(to),
+        _ => {}
+    }
+
+    match ratio {
+        RatioType::TokenBucket { capacity, .. } => {
+            pool.reset_bucket(capacity);
+        }
+        RatioType::Gcra { .. } | RatioType::Pacing { .. } => {
+            pool.reset_arrival();
+        }
+        RatioType::Gradient { initial, .. } => {
+            pool.reset_gradient(initial);
+        }
+        RatioType::SlidingWindowLog(_, _)
+        | RatioType::SlidingWindowCounter(_, _)
+        | RatioType::Composite { .. } => {
+            pool.reset_window();
+        }
+        _ => {}
+    };
+
+    // the refills already scheduled will pick up the new flavor, unless they're due by the period
+    // of the previous one, e.g. an hour away while the new one refills every few milliseconds.
+    if flavor.refill_period() != ratio.refill_period() {
+        pool.restart_refill();
+    } else if ratio.is_refilled() {
+        pool.start_refill();
+    }
+
+    // a future that has claimed more tokens than the pool can now hold shall not hold up the line.
+    pool.drop_unmet_claim();
+}
+
+/// Wrap the future such that it will only be polled once the tokens of the given cost have been
+/// obtained from the pool, waiting in line by the given rank if it has to.
+pub(crate) fn guard<R, F>(
+    pool: &Arc<InnerPool>,
+    policy: &KeeperPolicy,
+    cost: usize,
+    rank: Rank,
+    fut: F,
+) -> Option<impl Future<Output = R>>
+where
+    R: Send + 'static,
+    F: Future<Output = R> + 'static,
+{
+    // the future can never be admitted if it costs more than the pool can ever hold.
+    if pool.is_closed() || cost == 0 || cost > pool.max_cost() {
+        return None;
+    }
+
+    // the line won't move fast enough for us, turn the future down right away: it can't be turned
+    // away once issued.
+    if pool.can_wait().is_err() {
+        return None;
+    }
+
+    let mut fut_wrapper = Some(fut);
+
+    let mut ticket: Ticket<R, F> = match policy.token {
+        TokenPolicy::Cooperative => Ticket::new(Arc::clone(pool), fut_wrapper.take()),
+        TokenPolicy::Preemptive => Ticket::new(Arc::clone(pool), None),
+    };
+
+    if policy.spin != SpinPolicy::InplaceWait {
+        ticket.set_spin_policy(policy.spin);
+    }
+
+    ticket.set_cost(cost);
+    ticket.set_rank(rank);
+
+    // for the same reason, if the line is bounded, take the tokens right away if we can, or hold
+    // a place in line from now on, such that the future is only turned down if the line is full.
+    if policy.max_waiters < usize::MAX && !ticket.admit_now() {
+        if !pool.reserve_place(policy.max_waiters) {
+            return None;
+        }
+
+        ticket.hold_place();
+    }
+
+    let fut = async move {
+        // with no signal to interrupt it, the ticket only resolves once it's got the tokens.
+        let envelope = loop {
+            if let Ok(envelope) = (&mut ticket).await {
+                break envelope;
+            }
+        };
+
+        let _stub = match envelope {
+            Envelope::Stub(t) => t,
+            Envelope::Output(val) => return val,
+        };
+
+        fut_wrapper.take().unwrap().await
+    };
+
+    Some(fut)
+}
+
+/// Wrap the future with the tokens of the given cost if they can be taken right now, or hand the
+/// future back otherwise.
+pub(crate) fn try_guard<R, F>(
+    pool: &Arc<InnerPool>,
+    cost: usize,
+    fut: F,
+) -> Result<impl Future<Output = R>, F>
+where
+    R: Send + 'static,
+    F: Future<Output = R> + 'static,
+{
+    if pool.is_closed() || cost == 0 || cost > pool.max_cost() {
+        return Err(fut);
+    }
+
+    // not a ticket waiting in line, so we won't stake a claim on the tokens either.
+    if pool.request_token(0, cost) {
+        let mut stub = TicketStub::unstarted(Arc::clone(pool), cost);
+
+        return Ok(async move {
+            // the round-trip time counts from the first poll, not from the issuance.
+            stub.start();
+            fut.await
+        });
+    }
+
+    Err(fut)
+}
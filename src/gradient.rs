@@ -0,0 +1,73 @@
+/// How much of the newly estimated limit is taken in on every sample, such that the limit won't
+/// jump around with every single slow or fast future.
+const SMOOTHING: f64 = 0.2;
+
+/// The number of samples the baseline round-trip time is averaged over, such that it follows a
+/// lasting change of the latency on the other end, instead of holding on to the best one ever seen.
+const BASELINE_SAMPLES: u64 = 600;
+
+/// The baseline is a plain average of the first samples, before it's settled enough to be
+/// averaged exponentially.
+const WARMUP_SAMPLES: u64 = 10;
+
+/// How far the round-trip time may rise above the baseline before the limit shrinks.
+const TOLERANCE: f64 = 1.5;
+
+/// The bookkeeping for the `Gradient` flavor, all round-trip times are in nanoseconds.
+pub(crate) struct LatencyGradient {
+    baseline: f64,
+    samples: u64,
+    limit: f64,
+}
+
+impl LatencyGradient {
+    pub(crate) fn new(initial: usize) -> Self {
+        LatencyGradient {
+            baseline: 0.,
+            samples: 0,
+            limit: initial as f64,
+        }
+    }
+
+    pub(crate) fn reset(&mut self, initial: usize) {
+        *self = LatencyGradient::new(initial);
+    }
+
+    /// Take in the round-trip time of a guarded future, and return the new limit. The limit is
+    /// scaled by the gradient between the long-term average and the current round-trip time, such
+    /// that it shrinks once the futures queue up on the other end, plus a headroom of the square
+    /// root of the limit to keep probing for more capacity.
+    pub(crate) fn update(&mut self, rtt: u64, min: usize, max: usize) -> usize {
+        let rtt = rtt.max(1) as f64;
+        self.track_baseline(rtt);
+
+        let gradient = (TOLERANCE * self.baseline / rtt).clamp(0.5, 1.);
+        let estimated = self.limit * gradient + self.limit.sqrt();
+
+        self.limit = (self.limit * (1. - SMOOTHING) + estimated * SMOOTHING)
+            .max(min as f64)
+            .min(max as f64);
+
+        self.limit as usize
+    }
+
+    /// Average the round-trip time into the baseline, which follows a lasting rise of the latency
+    /// within `BASELINE_SAMPLES`, and a drop of it faster than that, such that the limit won't be
+    /// pinned either way.
+    fn track_baseline(&mut self, rtt: f64) {
+        self.samples += 1;
+
+        if self.samples <= WARMUP_SAMPLES {
+            self.baseline += (rtt - self.baseline) / self.samples as f64;
+            return;
+        }
+
+        let factor = 2. / (BASELINE_SAMPLES + 1) as f64;
+        self.baseline += (rtt - self.baseline) * factor;
+
+        // the latency has dropped for good, catch up with it quicker.
+        if self.baseline > 2. * rtt {
+            self.baseline *= 0.95;
+        }
+    }
+}
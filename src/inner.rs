@@ -1,36 +1,80 @@
-use crate::threads_queue::WaitingList;
-use crate::RatioType;
+use crate::gradient::LatencyGradient;
+use crate::threads_queue::{Grant, Seat, WaitingList};
+use crate::clock::Clock;
+use crate::window::SlidingWindow;
+use crate::{InterruptedReason, OverflowPolicy, QueueDiscipline, RatioType};
 use std::cmp::Ordering as Ord;
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock, Weak};
 use std::task::Waker;
-use std::thread;
 use std::time::Duration;
-use std::sync::RwLock;
 
 static UID: AtomicUsize = AtomicUsize::new(1);
 
+/// A claim staked by a ticket asking for more than one token, such that the tokens can pile up
+/// for it instead of being taken away by the tickets asking for less.
+struct Claim {
+    ticket: usize,
+    cost: usize,
+    waker: Option<Waker>,
+}
+
 pub(crate) struct InnerPool {
     pool_id: usize,
     closed: AtomicBool,
     token_counts: AtomicUsize,
     deficit: AtomicUsize,
+    in_flight: AtomicUsize,
     //    parking_lot: ThreadsQueue,
     waiting_list: WaitingList,
+    reserved: AtomicUsize,
+    discipline: RwLock<QueueDiscipline>,
     flavor: (RwLock<RatioType>, AtomicBool),
+    clock: Arc<dyn Clock>,
+    stamp: AtomicU64,
+    tat: AtomicU64,
+    armed: AtomicU64,
+    refilling: AtomicU64,
+    schedules: AtomicU64,
+    window: Mutex<SlidingWindow>,
+    gradient: Mutex<LatencyGradient>,
+    claim: (Mutex<Option<Claim>>, AtomicUsize),
+    parent: Option<Arc<InnerPool>>,
+    this: Weak<InnerPool>,
 }
 
 impl InnerPool {
-    pub(crate) fn new(size: usize, flavor: RatioType) -> Self {
+    pub(crate) fn new(
+        size: usize,
+        flavor: RatioType,
+        parent: Option<Arc<InnerPool>>,
+        clock: Arc<dyn Clock>,
+    ) -> Arc<Self> {
         let is_static_ratio = flavor.is_static_ratio();
+        let now = clock.now().as_nanos() as u64;
 
-        InnerPool {
+        Arc::new_cyclic(|this| InnerPool {
             pool_id: UID.fetch_add(1, Ordering::SeqCst),
             closed: AtomicBool::from(false),
             token_counts: AtomicUsize::new(size),
             deficit: AtomicUsize::new(0),
-            waiting_list: WaitingList::new(),
+            in_flight: AtomicUsize::new(flavor.in_flight_cap().unwrap_or(0)),
+            waiting_list: WaitingList::new(Arc::clone(&clock)),
+            reserved: AtomicUsize::new(0),
+            discipline: RwLock::new(QueueDiscipline::Relaxed),
             flavor: (RwLock::new(flavor), AtomicBool::new(is_static_ratio)),
-        }
+            clock,
+            stamp: AtomicU64::new(now),
+            tat: AtomicU64::new(0),
+            armed: AtomicU64::new(0),
+            refilling: AtomicU64::new(0),
+            schedules: AtomicU64::new(0),
+            window: Mutex::new(SlidingWindow::new()),
+            gradient: Mutex::new(LatencyGradient::new(flavor.in_flight_cap().unwrap_or(0))),
+            claim: (Mutex::new(None), AtomicUsize::new(0)),
+            parent,
+            this: this.clone(),
+        })
     }
 
     #[inline]
@@ -44,6 +88,11 @@ impl InnerPool {
         while let Some(waker) = self.waiting_list.dequeue() {
             waker.wake();
         }
+
+        let mut claim = self.claim.0.lock().expect("the claim is corrupted ...");
+        if let Some(waker) = claim.as_mut().and_then(|c| c.waker.take()) {
+            waker.wake();
+        }
     }
 
     #[inline]
@@ -60,27 +109,851 @@ impl InnerPool {
         *self.flavor.0.write().expect("the rate limit controller is corrupted ...") = flavor;
     }
 
+    pub(crate) fn set_discipline(&self, discipline: QueueDiscipline) {
+        *self
+            .discipline
+            .write()
+            .expect("the waiting list is corrupted ...") = discipline;
+        self.waiting_list.set_discipline(discipline);
+
+        // the tokens may be available already, which shall go to the line first.
+        if !self.waiting_list.is_empty() {
+            self.wake_up_many(1);
+        }
+    }
+
+    pub(crate) fn set_aging(&self, aging: Option<Duration>) {
+        self.waiting_list.set_aging(aging);
+    }
+
+    /// Check if the tokens are handed to the line directly, such that no one can barge in. Even
+    /// the `Relaxed` line is served strictly while a ranked ticket is waiting, or it could be
+    /// overtaken by the tickets it's ranked ahead of.
+    #[inline]
+    fn is_strict(&self) -> bool {
+        *self
+            .discipline
+            .read()
+            .expect("the waiting list is corrupted ...")
+            != QueueDiscipline::Relaxed
+            || self.waiting_list.has_ranked()
+    }
+
+    /// The most tokens a single future can ask for, such that it can ever be admitted by this pool
+    /// and all its ancestors.
+    pub(crate) fn max_cost(&self) -> usize {
+        let cost = self.get_flavor().max_cost();
+
+        match self.parent.as_ref() {
+            Some(parent) => cost.min(parent.max_cost()),
+            None => cost,
+        }
+    }
+
     pub(crate) fn static_rebalance(&self, from: usize, to: usize) {
         match from.cmp(&to) {
-            Ord::Greater => { self.token_counts.fetch_add(to - from, Ordering::SeqCst); },
-            Ord::Less => { self.token_counts.fetch_add(to - from, Ordering::SeqCst); },
+            Ord::Greater => {
+                // take back the tokens available right now, and the rest from the holders once
+                // they return the tokens.
+                let shrink = from - to;
+                let taken = self
+                    .token_counts
+                    .fetch_update(Ordering::SeqCst, Ordering::Acquire, |curr| {
+                        Some(curr - curr.min(shrink))
+                    })
+                    .map_or(0, |curr| curr.min(shrink));
+
+                self.deficit.fetch_add(shrink - taken, Ordering::SeqCst);
+            },
+            Ord::Less => {
+                let grow = to - from;
+                self.add_token(grow - self.pay_deficit(grow));
+            },
             _ => { /* if balanced, do nothing ... */ },
         };
     }
 
-    fn recover_one(&self) {
-        // return the token back
-        self.token_counts.fetch_add(1, Ordering::AcqRel);
+    /// Move the in-flight cap to the given size, with the tokens already handed out under the
+    /// current cap settled.
+    pub(crate) fn resize_in_flight(&self, to: usize) {
+        let from = self.in_flight.swap(to, Ordering::SeqCst);
+        self.static_rebalance(from, to);
+    }
+
+    /// Start over with the in-flight cap of the given size, when no token has been handed out
+    /// under a cap before.
+    pub(crate) fn reset_in_flight(&self, to: usize) {
+        self.in_flight.store(to, Ordering::SeqCst);
+        self.deficit.store(0, Ordering::SeqCst);
+        self.reset_token(to);
+    }
+
+    /// Feed the outcome of a guarded future to the adaptive flavors: the in-flight cap grows by one
+    /// on success and is cut by the backoff ratio on failure.
+    pub(crate) fn on_outcome(&self, success: bool) {
+        let (min, max, backoff) = match self.get_flavor() {
+            RatioType::Aimd {
+                min, max, backoff, ..
+            } => (min, max, backoff),
+            _ => return,
+        };
+
+        let next = |limit: usize| {
+            if success {
+                // only grow the cap while it's mostly in use, otherwise a light load would
+                // grow it all the way to the max without ever testing it.
+                if self.token_counts.load(Ordering::Acquire) * 2 > limit {
+                    return limit;
+                }
+
+                (limit + 1).min(max)
+            } else {
+                ((limit as f64 * backoff) as usize).max(min).min(max)
+            }
+        };
+
+        let mut limit = self.in_flight.load(Ordering::Acquire);
+
+        loop {
+            let to = next(limit);
+            if to == limit {
+                return;
+            }
+
+            match self
+                .in_flight
+                .compare_exchange(limit, to, Ordering::SeqCst, Ordering::Acquire)
+            {
+                Ok(_) => {
+                    self.static_rebalance(limit, to);
+                    return;
+                }
+                Err(curr) => limit = curr,
+            }
+        }
+    }
+
+    pub(crate) fn reset_bucket(&self, capacity: usize) {
+        // start refilling from now on, and make sure we don't hold more than the bucket can take.
+        self.stamp.store(self.elapsed(), Ordering::SeqCst);
+
+        let _ = self
+            .token_counts
+            .fetch_update(Ordering::SeqCst, Ordering::Acquire, |curr| {
+                if curr > capacity {
+                    Some(capacity)
+                } else {
+                    None
+                }
+            });
+    }
+
+    pub(crate) fn refill_bucket(&self, capacity: usize, refill: usize, per: Duration) {
+        let per = per.as_nanos().max(1);
+
+        loop {
+            let last = self.stamp.load(Ordering::Acquire);
+            let now = self.elapsed();
+            if now <= last {
+                return;
+            }
+
+            let earned = u128::from(now - last) * refill as u128 / per;
+            if earned == 0 {
+                return;
+            }
+
+            // carry the fraction of the token that's not yet earned over to the next refill; if
+            // we've been idle for long enough to fill the whole bucket, there's nothing to carry.
+            let next = if earned >= capacity as u128 {
+                now
+            } else {
+                last + (earned * per / refill as u128) as u64
+            };
+
+            if self
+                .stamp
+                .compare_exchange(last, next, Ordering::SeqCst, Ordering::Relaxed)
+                .is_err()
+            {
+                // someone else has refilled the bucket, check again.
+                continue;
+            }
+
+            let earned = earned.min(capacity as u128) as usize;
+            let res = self
+                .token_counts
+                .fetch_update(Ordering::SeqCst, Ordering::Acquire, |curr| {
+                    if curr >= capacity {
+                        None
+                    } else {
+                        Some((curr + earned).min(capacity))
+                    }
+                });
+
+            if let Ok(prev) = res {
+                self.wake_up_many((prev + earned).min(capacity) - prev);
+            }
+
+            return;
+        }
+    }
+
+    pub(crate) fn reset_arrival(&self) {
+        // forget about the history, the next token is available right away.
+        self.tat.store(self.elapsed(), Ordering::SeqCst);
+    }
+
+    pub(crate) fn reset_gradient(&self, initial: usize) {
+        self.gradient
+            .lock()
+            .expect("the rate limit controller is corrupted ...")
+            .reset(initial);
+    }
+
+    pub(crate) fn reset_window(&self) {
+        self.window
+            .lock()
+            .expect("the rate limit controller is corrupted ...")
+            .reset();
+    }
+
+    /// Decide if a token can be admitted right now, or when it can be admitted otherwise, for the
+    /// flavors that admit tokens on request instead of counting them. The token is only taken if
+    /// `take` is set.
+    fn admit(&self, flavor: RatioType, cost: usize, take: bool) -> Option<Result<(), u64>> {
+        let res = match flavor {
+            RatioType::Pacing { count, per, .. } => {
+                return self.admit(
+                    RatioType::Gcra {
+                        rate: count,
+                        per,
+                        burst: 1,
+                    },
+                    cost,
+                    take,
+                );
+            }
+            RatioType::Gcra { rate, per, burst } => {
+                if take {
+                    self.gcra_admit(rate, per, burst, cost)
+                } else {
+                    let (_, tolerance) = gcra_params(rate, per, burst);
+                    let at = self.tat.load(Ordering::Acquire).saturating_sub(tolerance);
+
+                    if at <= self.elapsed() {
+                        Ok(())
+                    } else {
+                        Err(at)
+                    }
+                }
+            }
+            RatioType::SlidingWindowLog(count, window)
+            | RatioType::Composite {
+                count, per: window, ..
+            } => self
+                .window
+                .lock()
+                .expect("the rate limit controller is corrupted ...")
+                .check_log(self.elapsed(), count, window, cost, take),
+            RatioType::SlidingWindowCounter(count, window) => self
+                .window
+                .lock()
+                .expect("the rate limit controller is corrupted ...")
+                .check_counter(self.elapsed(), count, window, cost, take),
+            _ => return None,
+        };
+
+        Some(res)
+    }
+
+    /// Decide if a token can be admitted right now by the theoretical arrival time (TAT) of the
+    /// next token; if not, return the moment when it will be admitted. A token costing more than
+    /// one pays its price forward, i.e. the tokens coming after it will be admitted later.
+    fn gcra_admit(&self, rate: usize, per: Duration, burst: usize, cost: usize) -> Result<(), u64> {
+        let (interval, tolerance) = gcra_params(rate, per, burst);
+        let mut tat = self.tat.load(Ordering::Acquire);
+
+        loop {
+            let now = self.elapsed();
+            if now.saturating_add(tolerance) < tat {
+                return Err(tat - tolerance);
+            }
+
+            match self.tat.compare_exchange(
+                tat,
+                tat.max(now).saturating_add(interval.saturating_mul(cost as u64)),
+                Ordering::SeqCst,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return Ok(()),
+                Err(curr) => tat = curr,
+            }
+        }
+    }
+
+    /// The moment when the next token(s) of the given cost will be admitted, if the flavor admits
+    /// tokens on request instead of counting them.
+    fn next_admission(&self, cost: usize) -> Option<u64> {
+        let flavor = self.get_flavor();
+
+        match self.admit(flavor, cost, false)? {
+            // the admission is open, the line is blocked by the counter, which will wake it up
+            // when a token is returned.
+            Ok(()) if flavor.is_counted() => None,
+            Ok(()) => Some(self.elapsed()),
+            Err(at) => Some(at),
+        }
+    }
+
+    /// Set an alarm to go off at the given moment, unless an earlier one is already on the way.
+    fn arm(&self, at: u64) {
+        let at = at.max(1);
+        let mut curr = self.armed.load(Ordering::Acquire);
+
+        loop {
+            if curr != 0 && curr <= at {
+                return;
+            }
+
+            match self
+                .armed
+                .compare_exchange(curr, at, Ordering::SeqCst, Ordering::Acquire)
+            {
+                Ok(_) => break,
+                Err(val) => curr = val,
+            }
+        }
+
+        let this = self.this.clone();
+        self.clock.schedule(
+            Duration::from_nanos(at),
+            Box::new(move || {
+                if let Some(pool) = this.upgrade() {
+                    pool.on_alarm();
+                }
+            }),
+        );
+    }
+
+    /// Start refilling the tokens on the schedule of the flavor, unless the refills have been
+    /// scheduled already, such that the pool has exactly one refill schedule at any time.
+    pub(crate) fn start_refill(&self) {
+        let schedule = self.schedules.fetch_add(1, Ordering::AcqRel) + 1;
+
+        if self
+            .refilling
+            .compare_exchange(0, schedule, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+        {
+            self.on_refill(schedule);
+        }
+    }
+
+    /// Start the refills over on a new schedule, e.g. once their period has changed, such that the
+    /// refills scheduled by the previous period are ignored once they're due.
+    pub(crate) fn restart_refill(&self) {
+        let schedule = self.schedules.fetch_add(1, Ordering::AcqRel) + 1;
+
+        self.refilling.store(schedule, Ordering::Release);
+        self.on_refill(schedule);
+    }
+
+    /// Keep refilling the bucket while someone is waiting for its tokens, since no one else will
+    /// collect the tokens earned for them.
+    fn keep_refilling(&self) {
+        if let RatioType::TokenBucket { .. } = self.get_flavor() {
+            self.start_refill();
+        }
+    }
+
+    /// Check if the tokens shall be refilled on schedule: a bucket is only refilled on schedule
+    /// while someone is waiting for its tokens, otherwise the one asking for a token next collects
+    /// the tokens earned so far.
+    fn needs_refill(&self) -> bool {
+        match self.get_flavor() {
+            RatioType::FixedRate(_, _) => true,
+            RatioType::TokenBucket { .. } => {
+                !self.waiting_list.is_empty() || self.claim.1.load(Ordering::Acquire) != 0
+            }
+            _ => false,
+        }
+    }
+
+    /// Refill the tokens by the flavor, and schedule the next refill on the shared timer. The
+    /// schedule stops once the flavor no longer refills, no one is waiting for the tokens of a
+    /// bucket, the schedule has been started over, or the pool has been dropped.
+    fn on_refill(&self, schedule: u64) {
+        if self.refilling.load(Ordering::Acquire) != schedule {
+            return;
+        }
+
+        let flavor = self.get_flavor();
+        let next = match flavor {
+            RatioType::FixedRate(count, _) => {
+                // set amount of tokens allowed in this time slab to the pool.
+                self.reset_token(count);
+                flavor.refill_period()
+            }
+            RatioType::TokenBucket {
+                capacity,
+                refill,
+                per,
+            } => {
+                // drop the tokens earned so far into the bucket, then wait till the next token
+                // shall be earned.
+                self.refill_bucket(capacity, refill, per);
+                flavor.refill_period().filter(|_| self.needs_refill())
+            }
+            _ => None,
+        };
+
+        let next = match next {
+            Some(next) => next,
+            None => {
+                // leave it to the new schedule if the refills have been started over meanwhile.
+                if self
+                    .refilling
+                    .compare_exchange(schedule, 0, Ordering::AcqRel, Ordering::Acquire)
+                    .is_err()
+                {
+                    return;
+                }
+
+                // someone may have got in line, or the flavor been switched back, meanwhile, carry
+                // on unless someone else has started over.
+                if self.needs_refill() {
+                    self.start_refill();
+                }
+
+                return;
+            }
+        };
+
+        let this = self.this.clone();
+        self.clock.schedule(
+            self.clock.now() + next,
+            Box::new(move || {
+                if let Some(pool) = this.upgrade() {
+                    pool.on_refill(schedule);
+                }
+            }),
+        );
+    }
+
+    pub(crate) fn on_alarm(&self) {
+        self.armed.store(0, Ordering::SeqCst);
+
+        // the line is held up for the claimant, no one else shall be woken.
+        if self.serve_claim() {
+            return;
+        }
+
+        // the next token can be admitted now, let the first one in line have it.
+        if self.is_strict() {
+            self.hand_off();
+        } else if let Some(waker) = self.waiting_list.dequeue() {
+            waker.wake();
+        }
+
+        // in case the woken ticket no longer wants the token, make sure the rest of the line will
+        // still be served.
+        if !self.waiting_list.is_empty() {
+            if let Some(at) = self.next_admission(1) {
+                self.arm(at.max(self.elapsed()) + 1);
+            }
+        }
+    }
+
+    /// Take the tokens of the given cost from the counter if there are enough of them, without
+    /// ever waiting for them.
+    fn take_token(&self, cost: usize) -> bool {
+        self.token_counts
+            .fetch_update(Ordering::SeqCst, Ordering::Acquire, |curr| curr.checked_sub(cost))
+            .is_ok()
+    }
+
+    /// Take the tokens of the given cost from the counter and/or by the admission of the flavor,
+    /// or return false right away if they can't be taken yet.
+    fn acquire(&self, flavor: RatioType, cost: usize) -> bool {
+        // the flavors counting the tokens shall take them from the counter first.
+        if flavor.is_counted() && !self.take_token(cost) {
+            return false;
+        }
+
+        match self.admit(flavor, cost, true) {
+            None | Some(Ok(())) => true,
+            Some(Err(_)) => {
+                // put the tokens back for now, no one shall be woken up for them since the
+                // admission is still closed.
+                if flavor.is_counted() {
+                    self.token_counts.fetch_add(cost, Ordering::AcqRel);
+                }
+
+                false
+            }
+        }
+    }
+
+    /// Feed the round-trip time of a guarded future, i.e. from the moment it was admitted till it
+    /// returned the token, to the latency based flavors.
+    pub(crate) fn on_rtt(&self, rtt: u64) {
+        let (min, max) = match self.get_flavor() {
+            RatioType::Gradient { min, max, .. } => (min, max),
+            _ => return,
+        };
+
+        // hold on to the lock while resizing, such that the limits are applied in order.
+        let mut gradient = self
+            .gradient
+            .lock()
+            .expect("the rate limit controller is corrupted ...");
+
+        let to = gradient.update(rtt, min, max);
+        if to != self.in_flight.load(Ordering::Acquire) {
+            self.resize_in_flight(to);
+        }
+    }
+
+    /// Give the tokens taken by a ticket that no longer wants them to the next one in line, as if
+    /// they were never taken from this pool and all its ancestors.
+    pub(crate) fn hand_back(&self, cost: usize) {
+        if let Some(parent) = self.parent.as_ref() {
+            parent.hand_back(cost);
+        }
+
+        self.refund(self.get_flavor(), cost);
+        self.wake_up_many(cost);
+    }
+
+    /// Hand the available tokens to the ones in line, in the order they arrived, such that no
+    /// newcomer can take the tokens first.
+    fn hand_off(&self) {
+        let flavor = self.get_flavor();
+        let mut reopens = None;
+
+        let woken = self.waiting_list.hand_off(|ticket, cost| {
+            if !self.acquire(flavor, cost) {
+                // the admission may be closed rather than the tokens running out, then no one will
+                // return a token to wake up the line, check again once it reopens, or right away if
+                // it's reopened since.
+                reopens = match self.admit(flavor, cost, false) {
+                    Some(Err(at)) => Some(at),
+                    Some(Ok(())) if self.affordable(flavor, cost) => Some(self.elapsed()),
+                    _ => None,
+                };
+
+                return Grant::Unavailable;
+            }
+
+            // the ticket shall wait in the ancestor's line instead.
+            if let Some(parent) = self.parent.as_ref() {
+                if !parent.request_token(ticket, cost) {
+                    self.refund(flavor, cost);
+                    return Grant::HeldUp;
+                }
+            }
+
+            Grant::Taken
+        });
+
+        woken.into_iter().for_each(Waker::wake);
+
+        if let Some(at) = reopens {
+            self.arm(at);
+        }
+    }
+
+    /// Make sure a ticket about to get in line can wait there, such that no more than
+    /// `max_waiters` tickets are waiting in line, or holding a place in it.
+    pub(crate) fn make_room(
+        &self,
+        max_waiters: usize,
+        overflow: OverflowPolicy,
+    ) -> Result<(), InterruptedReason> {
+        if self.waiting_list.len() + self.reserved.load(Ordering::Acquire) < max_waiters {
+            return Ok(());
+        }
+
+        if overflow == OverflowPolicy::EvictOldest && self.waiting_list.evict_oldest() {
+            return Ok(());
+        }
+
+        Err(InterruptedReason::QueueFull)
+    }
+
+    /// Hold a place in line for a newly issued ticket that can't be turned away once it gets to
+    /// the line, and return false if the line is already full.
+    pub(crate) fn reserve_place(&self, max_waiters: usize) -> bool {
+        self.reserved
+            .fetch_update(Ordering::SeqCst, Ordering::Acquire, |reserved| {
+                if self.waiting_list.len() + reserved < max_waiters {
+                    Some(reserved + 1)
+                } else {
+                    None
+                }
+            })
+            .is_ok()
+    }
+
+    /// Give up the place held in line, i.e. the ticket has got in line, or needs no place in it.
+    pub(crate) fn release_place(&self) {
+        self.reserved.fetch_sub(1, Ordering::AcqRel);
+    }
+
+    /// The number of tickets waiting in line for the tokens of this pool.
+    pub(crate) fn waiting(&self) -> usize {
+        self.waiting_list.len()
+    }
+
+    /// Take the ticket out of the line of this pool and all its ancestors.
+    pub(crate) fn remove_waiter(&self, ticket: usize) {
+        if let Some(parent) = self.parent.as_ref() {
+            parent.remove_waiter(ticket);
+        }
+
+        self.waiting_list.remove(ticket);
+
+        // the ticket may have been holding up the head of the line, serve the next one.
+        if self.is_strict() && !self.waiting_list.is_empty() {
+            self.hand_off();
+        }
+    }
+
+    /// Pass the wake-up a ticket has received, but no longer needs, on to the next one in line.
+    pub(crate) fn pass_on(&self) {
+        if let Some(parent) = self.parent.as_ref() {
+            parent.pass_on();
+        }
+
+        if !self.waiting_list.is_empty() {
+            self.wake_up_many(1);
+        }
+    }
+
+    /// Get the ticket in line to wait for the tokens. The tokens can be handed to the ticket
+    /// directly only if `grantable` is set, i.e. when the tokens of all the pools down to the
+    /// ticket's own are to be taken.
+    fn wait_in_line(&self, seat: Arc<Seat>, cost: usize, grantable: bool) {
+        // if we can afford the tokens ourselves, we've been held up by an ancestor, so wait in
+        // their line instead.
+        if let Some(parent) = self.parent.as_ref() {
+            if self.affordable(self.get_flavor(), cost) {
+                // the seat is only good for the tokens of this pool, the ancestors shall wake us
+                // up to take theirs ourselves.
+                parent.wait_in_line(seat, cost, false);
+                return;
+            }
+        }
+
+        let ticket = seat.ticket();
+        if self.claim.1.load(Ordering::Acquire) == ticket {
+            let mut guard = self.claim.0.lock().expect("the claim is corrupted ...");
+
+            if let Some(claim) = guard.as_mut().filter(|c| c.ticket == ticket) {
+                claim.waker = seat.waker();
+                drop(guard);
+
+                self.keep_refilling();
+
+                // tokens may have piled up before we could register, check again.
+                self.serve_claim();
+                return;
+            }
+        }
+
+        self.waiting_list.enqueue(seat, cost, grantable);
+        self.keep_refilling();
+
+        // the tokens may have been returned before we could get in line, and no one would wake us
+        // up for them.
+        if self.affordable(self.get_flavor(), cost) {
+            self.wake_up_many(1);
+        }
+
+        // no one will return or generate tokens for the flavors admitting on request, we shall
+        // set the alarm to wake up the line when the next token can be admitted.
+        if let Some(at) = self.next_admission(cost) {
+            self.arm(at);
+        }
+    }
+
+    /// Pay the deficit with the tokens of the given count, and return the number of tokens used.
+    fn pay_deficit(&self, count: usize) -> usize {
+        self.deficit
+            .fetch_update(Ordering::SeqCst, Ordering::Acquire, |deficit| {
+                if deficit == 0 {
+                    None
+                } else {
+                    Some(deficit - deficit.min(count))
+                }
+            })
+            .map_or(0, |deficit| deficit.min(count))
+    }
+
+    /// Give back the tokens of the given cost that have just been taken, as if they were never
+    /// admitted. No one is woken up here, which is up to the caller.
+    fn refund(&self, flavor: RatioType, cost: usize) {
+        if flavor.is_counted() {
+            self.token_counts.fetch_add(cost, Ordering::AcqRel);
+        }
+
+        match flavor {
+            RatioType::Gcra { rate, per, .. } | RatioType::Pacing { count: rate, per, .. } => {
+                let (interval, _) = gcra_params(rate, per, 1);
+                let _ = self
+                    .tat
+                    .fetch_update(Ordering::SeqCst, Ordering::Acquire, |tat| {
+                        Some(tat.saturating_sub(interval.saturating_mul(cost as u64)))
+                    });
+            }
+            RatioType::SlidingWindowLog(_, _) | RatioType::Composite { .. } => self
+                .window
+                .lock()
+                .expect("the rate limit controller is corrupted ...")
+                .refund_log(cost),
+            RatioType::SlidingWindowCounter(_, _) => self
+                .window
+                .lock()
+                .expect("the rate limit controller is corrupted ...")
+                .refund_counter(cost),
+            _ => {}
+        }
+    }
+
+    /// Check if the tokens of the given cost could be taken right now.
+    fn affordable(&self, flavor: RatioType, cost: usize) -> bool {
+        if flavor.is_counted() && self.token_counts.load(Ordering::Acquire) < cost {
+            return false;
+        }
+
+        self.admit(flavor, cost, false)
+            .map_or(true, |res| res.is_ok())
+    }
+
+    fn stake_claim(&self, flavor: RatioType, ticket: usize, cost: usize) {
+        // the claim can't be fulfilled by the flavor, don't let it hold up the line forever.
+        if ticket == 0 || cost <= 1 || flavor.is_arrival_based() || cost > flavor.max_cost() {
+            return;
+        }
+
+        // no one can overtake the ticket in line anyway.
+        if self.is_strict() {
+            return;
+        }
+
+        let mut claim = self.claim.0.lock().expect("the claim is corrupted ...");
+        if claim.is_none() {
+            *claim = Some(Claim {
+                ticket,
+                cost,
+                waker: None,
+            });
+
+            self.claim.1.store(ticket, Ordering::Release);
+        }
+    }
+
+    /// Drop the claim if it can no longer be met, i.e. the flavor has been changed to one with a
+    /// smaller `max_cost`, and wake up the claimant to try again, such that the line won't be held
+    /// up for good.
+    pub(crate) fn drop_unmet_claim(&self) {
+        let (ticket, waker) = {
+            let mut guard = self.claim.0.lock().expect("the claim is corrupted ...");
+            match guard.as_mut() {
+                Some(claim) if claim.cost > self.max_cost() => (claim.ticket, claim.waker.take()),
+                _ => return,
+            }
+        };
+
+        self.release_claim(ticket);
 
-        // if we have tickets waiting, wake them up and let them poll again.
-        if let Some(waker) = self.waiting_list.dequeue() {
+        if let Some(waker) = waker {
             waker.wake();
         }
     }
 
+    pub(crate) fn release_claim(&self, ticket: usize) {
+        // the ticket may have staked claims on the ancestors as well.
+        if let Some(parent) = self.parent.as_ref() {
+            parent.release_claim(ticket);
+        }
+
+        if self.claim.1.load(Ordering::Acquire) != ticket {
+            return;
+        }
+
+        {
+            let mut claim = self.claim.0.lock().expect("the claim is corrupted ...");
+            if claim.as_ref().map(|c| c.ticket) != Some(ticket) {
+                return;
+            }
+
+            *claim = None;
+            self.claim.1.store(0, Ordering::Release);
+        }
+
+        // the line has been held up for the claimant, let them try again.
+        let available = self.token_counts.load(Ordering::Acquire);
+        if self.get_flavor().is_counted() && available > 0 {
+            self.wake_up_many(available);
+        }
+
+        if !self.waiting_list.is_empty() {
+            if let Some(at) = self.next_admission(1) {
+                self.arm(at);
+            }
+        }
+    }
+
+    /// Wake up the claimant if its claim can be fulfilled now. Return if there's a pending claim,
+    /// i.e. the line is held up for the claimant.
+    fn serve_claim(&self) -> bool {
+        if self.claim.1.load(Ordering::Acquire) == 0 {
+            return false;
+        }
+
+        let mut guard = self.claim.0.lock().expect("the claim is corrupted ...");
+        let claim = match guard.as_mut() {
+            Some(claim) => claim,
+            None => return false,
+        };
+
+        let flavor = self.get_flavor();
+        if self.affordable(flavor, claim.cost) {
+            if let Some(waker) = claim.waker.take() {
+                waker.wake();
+            }
+        } else if let Some(at) = self.next_admission(claim.cost) {
+            // the claimant is waiting for the admission, check again when it's open.
+            self.arm(at);
+        }
+
+        true
+    }
+
+    #[inline]
+    pub(crate) fn elapsed(&self) -> u64 {
+        self.clock.now().as_nanos() as u64
+    }
+
+    pub(crate) fn clock(&self) -> Arc<dyn Clock> {
+        Arc::clone(&self.clock)
+    }
+
     fn wake_up_many(&self, count: usize) {
         assert!(count > 0);
 
+        // the line is held up for the claimant, no one else shall be woken.
+        if self.serve_claim() {
+            return;
+        }
+
+        if self.is_strict() {
+            self.hand_off();
+            return;
+        }
+
         let mut remainder = count;
         while let Some(waker) = self.waiting_list.dequeue() {
             waker.wake();
@@ -96,9 +969,10 @@ impl InnerPool {
 pub(crate) trait TokenFetcher {
     fn add_token(&self, count: usize);
     fn reset_token(&self, count: usize);
-    fn request_token(&self, fill_or_cancel: bool) -> bool;
-    fn return_token(&self);
-    fn enqueue(&self, waker: Waker);
+    fn request_token(&self, ticket: usize, cost: usize) -> bool;
+    fn return_token(&self, cost: usize);
+    fn can_wait(&self) -> Result<(), InterruptedReason>;
+    fn enqueue(&self, seat: &Arc<Seat>, cost: usize);
 }
 
 impl TokenFetcher for InnerPool {
@@ -119,75 +993,122 @@ impl TokenFetcher for InnerPool {
         }
     }
 
-    fn request_token(&self, immediate_or_cancel: bool) -> bool {
-        let mut curr = 1;
-        let mut attempts = 0;
+    /// Take the tokens of the given cost for the ticket if they're available right now, which
+    /// never blocks: if they're not, the ticket shall wait in line to be woken up for them.
+    fn request_token(&self, ticket: usize, cost: usize) -> bool {
+        let flavor = self.get_flavor();
+
+        // someone asking for more tokens is waiting for them to pile up, get in line after them.
+        let claimant = self.claim.1.load(Ordering::Acquire);
+        if claimant != 0 && claimant != ticket {
+            return false;
+        }
 
-        while let Err(val) =
-            self.token_counts
-                .compare_exchange(curr, curr - 1, Ordering::SeqCst, Ordering::Relaxed)
+        // no cutting in line, unless we're the first one in line.
+        if self.is_strict() && !self.waiting_list.is_head(ticket) {
+            return false;
+        }
+
+        if let RatioType::TokenBucket {
+            capacity,
+            refill,
+            per,
+        } = flavor
         {
-            if val == 0 && immediate_or_cancel {
+            // collect the tokens earned since the last refill before we take one.
+            self.refill_bucket(capacity, refill, per);
+        }
+
+        if !self.acquire(flavor, cost) {
+            self.stake_claim(flavor, ticket, cost);
+            return false;
+        }
+
+        // all the ancestors shall admit the tokens as well, otherwise we shall give ours back such
+        // that nothing is held while waiting for the ancestors.
+        if let Some(parent) = self.parent.as_ref() {
+            if !parent.request_token(ticket, cost) {
+                self.refund(flavor, cost);
+
+                // someone may have missed the tokens while we were holding them, unless no one
+                // could have taken them before us anyway.
+                if !self.is_strict() && !self.waiting_list.is_empty() {
+                    self.wake_up_many(cost);
+                }
+
                 return false;
             }
+        }
 
-            // mark the attempts
-            attempts += 1;
+        if claimant == ticket {
+            self.release_claim(ticket);
+        }
 
-            // token is available but we can't grab it just yet, or there's no token but we
-            // can't park since we're in some pool's main thread --> let's take a break from
-            // contentious competitions.
-            if attempts < 8 {
-                thread::sleep(Duration::from_micros(1 << attempts));
-            } else {
-                attempts = 1;
-                thread::yield_now();
+        // let the next in line know when it's their turn.
+        if !self.waiting_list.is_empty() {
+            if let Some(at) = self.next_admission(1) {
+                self.arm(at);
             }
-
-            // we can retry again right away, use
-            curr = if val > 0 { val } else { 1 };
         }
 
         true
     }
 
-    fn return_token(&self) {
-        // if not a static ratio flavor, we won't return the token back.
-        if !self.flavor.1.load(Ordering::Acquire) {
-            return;
+    fn return_token(&self, cost: usize) {
+        // the tokens have been taken from all the ancestors as well.
+        if let Some(parent) = self.parent.as_ref() {
+            parent.return_token(cost);
         }
 
-        // if a static ratio flavor, check if we're in debt first.
-        let mut deficit = self.deficit.load(Ordering::Acquire);
-        if deficit == 0 {
-            self.recover_one();
+        // if not a static ratio flavor, we won't return the token back.
+        if !self.flavor.1.load(Ordering::Acquire) {
             return;
         }
 
-        // be a Lannister and always pay your debt first
-        let mut attempts = 1;
-        while let Err(curr) = self.deficit.compare_exchange(
-            deficit,
-            deficit - 1,
-            Ordering::SeqCst,
-            Ordering::Relaxed,
-        ) {
-            if curr == 0 {
-                self.recover_one();
-                return;
-            }
-
-            deficit = curr;
+        // if a static ratio flavor, check if we're in debt first, and be a Lannister and always
+        // pay your debt first
+        let paid = self.pay_deficit(cost);
+        self.add_token(cost - paid);
+    }
 
-            thread::sleep(Duration::from_micros(1 << attempts));
+    fn can_wait(&self) -> Result<(), InterruptedReason> {
+        if let RatioType::Pacing {
+            count,
+            per,
+            max_delay: Some(max_delay),
+        } = self.get_flavor()
+        {
+            // everyone in line will be admitted one interval after another, see if we can still
+            // make it in time by the end of the line.
+            let (interval, _) = gcra_params(count, per, 1);
+            let now = self.elapsed();
+            let delay = self.next_admission(1).unwrap_or(now).saturating_sub(now)
+                + self.waiting_list.len() as u64 * interval;
 
-            if attempts < 8 {
-                attempts += 1;
+            if Duration::from_nanos(delay) > max_delay {
+                return Err(InterruptedReason::DelayExceeded);
             }
         }
+
+        Ok(())
     }
 
-    fn enqueue(&self, waker: Waker) {
-        self.waiting_list.enqueue(waker);
+    fn enqueue(&self, seat: &Arc<Seat>, cost: usize) {
+        self.wait_in_line(Arc::clone(seat), cost, true);
     }
 }
+
+/// The emission interval between 2 tokens, and the tolerance for tokens arriving ahead of their
+/// theoretical arrival time, both in nanoseconds.
+pub(crate) fn gcra_params(rate: usize, per: Duration, burst: usize) -> (u64, u64) {
+    let interval = (per.as_nanos() / rate.max(1) as u128).max(1) as u64;
+    (interval, interval.saturating_mul(burst.max(1) as u64 - 1))
+}
+
+/// The most tokens a single future can ask for at the given rate, i.e. the ones it pays forward
+/// shall be emitted within half of the time the nanoseconds can count (~146 years), such that the
+/// theoretical arrival time never overflows.
+pub(crate) fn gcra_max_cost(rate: usize, per: Duration) -> usize {
+    let (interval, _) = gcra_params(rate, per, 1);
+    (u64::MAX / 2 / interval).min(usize::MAX as u64) as usize
+}
@@ -0,0 +1,253 @@
+use crate::clock::{Clock, SystemClock};
+use crate::gatekeeper::{self, GateKeeperConfig, KeeperPolicy};
+use crate::inner::InnerPool;
+use crate::threads_queue::Rank;
+use crate::{OverflowPolicy, QueueDiscipline, RatioType, SpinPolicy, TokenPolicy};
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+struct KeyedPool {
+    pool: Arc<InnerPool>,
+    last_used: Duration,
+}
+
+/// A gatekeeper guarding each key with its own pool, such that the futures issued for different
+/// keys (i.e. users or tenants) won't compete for the same tokens. The pools are created from the
+/// template flavor upon the first issuance for the key, and are evicted once they have been idle
+/// for longer than the idle timeout.
+pub struct KeyedGateKeeper<K>
+where
+    K: Hash + Eq,
+{
+    template: RatioType,
+    discipline: QueueDiscipline,
+    aging: Option<Duration>,
+    pools: Mutex<(HashMap<K, KeyedPool>, Duration)>,
+    clock: Arc<dyn Clock>,
+    idle_timeout: Duration,
+    closed: AtomicBool,
+    policy: KeeperPolicy,
+}
+
+impl<K> KeyedGateKeeper<K>
+where
+    K: Hash + Eq,
+{
+    pub fn new(template: RatioType) -> Self {
+        Self::with_clock(template, Arc::new(SystemClock::new()))
+    }
+
+    /// Create a keyed gatekeeper whose pools, as well as the idle timeout, read the time from the
+    /// given clock.
+    pub fn with_clock(template: RatioType, clock: Arc<dyn Clock>) -> Self {
+        assert!(template.is_valid());
+
+        KeyedGateKeeper {
+            template,
+            discipline: QueueDiscipline::Relaxed,
+            aging: None,
+            pools: Mutex::new((HashMap::new(), clock.now())),
+            clock,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            closed: AtomicBool::new(false),
+            policy: Default::default(),
+        }
+    }
+
+    /// Set how long a key shall stay idle, i.e. with no future issued for it and none of its
+    /// futures still holding a token, before its pool is evicted. Note that an evicted key starts
+    /// over with a fresh pool, hence the timeout shall be longer than the window of the flavor.
+    pub fn set_idle_timeout(&mut self, timeout: Duration) {
+        self.idle_timeout = timeout;
+    }
+
+    pub fn issue<R, F>(&self, key: K, fut: F) -> Option<impl Future<Output = R>>
+    where
+        R: Send + 'static,
+        F: Future<Output = R> + 'static,
+    {
+        self.issue_weighted(key, 1, fut)
+    }
+
+    pub fn issue_weighted<R, F>(
+        &self,
+        key: K,
+        cost: usize,
+        fut: F,
+    ) -> Option<impl Future<Output = R>>
+    where
+        R: Send + 'static,
+        F: Future<Output = R> + 'static,
+    {
+        if self.is_closed() {
+            return None;
+        }
+
+        gatekeeper::guard(&self.pool_of(key), &self.policy, cost, Rank::default(), fut)
+    }
+
+    /// Issue the future only if a token is available for the key right now, otherwise hand the
+    /// future back.
+    pub fn try_issue<R, F>(&self, key: K, fut: F) -> Result<impl Future<Output = R>, F>
+    where
+        R: Send + 'static,
+        F: Future<Output = R> + 'static,
+    {
+        if self.is_closed() {
+            return Err(fut);
+        }
+
+        gatekeeper::try_guard(&self.pool_of(key), 1, fut)
+    }
+
+    /// Evict the keys that have been idle for longer than the idle timeout right away, and return
+    /// the number of keys evicted.
+    pub fn evict_idle(&self) -> usize {
+        let mut pools = self
+            .pools
+            .lock()
+            .expect("the keyed pools are corrupted ...");
+        let now = self.clock.now();
+
+        pools.1 = now;
+        Self::sweep(&mut pools.0, now, self.idle_timeout)
+    }
+
+    pub fn len(&self) -> usize {
+        self.pools
+            .lock()
+            .expect("the keyed pools are corrupted ...")
+            .0
+            .len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+
+        let pools = self
+            .pools
+            .lock()
+            .expect("the keyed pools are corrupted ...");
+        pools.0.values().for_each(|p| p.pool.close());
+    }
+
+    #[inline]
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    fn pool_of(&self, key: K) -> Arc<InnerPool> {
+        let mut pools = self
+            .pools
+            .lock()
+            .expect("the keyed pools are corrupted ...");
+        let now = self.clock.now();
+
+        // sweep the idle keys every now and then, such that they won't pile up.
+        if now.saturating_sub(pools.1) >= self.idle_timeout {
+            pools.1 = now;
+            Self::sweep(&mut pools.0, now, self.idle_timeout);
+        }
+
+        let (template, discipline, aging) = (self.template, self.discipline, self.aging);
+        let clock = &self.clock;
+        let entry = pools.0.entry(key).or_insert_with(|| {
+            let pool = gatekeeper::build_pool(template, None, Arc::clone(clock));
+            pool.set_discipline(discipline);
+            pool.set_aging(aging);
+
+            KeyedPool {
+                pool,
+                last_used: now,
+            }
+        });
+
+        entry.last_used = now;
+        Arc::clone(&entry.pool)
+    }
+
+    fn sweep(pools: &mut HashMap<K, KeyedPool>, now: Duration, timeout: Duration) -> usize {
+        let before = pools.len();
+
+        // if we hold the only reference to the pool, no ticket or stub is out there for the key.
+        pools.retain(|_, p| {
+            Arc::strong_count(&p.pool) > 1 || now.saturating_sub(p.last_used) < timeout
+        });
+
+        before - pools.len()
+    }
+}
+
+impl<K> GateKeeperConfig for KeyedGateKeeper<K>
+where
+    K: Hash + Eq,
+{
+    fn set_ratio(&mut self, ratio: RatioType) {
+        assert!(ratio.is_valid());
+        self.template = ratio;
+
+        let pools = self
+            .pools
+            .lock()
+            .expect("the keyed pools are corrupted ...");
+        pools
+            .0
+            .values()
+            .for_each(|p| gatekeeper::apply_ratio(&p.pool, ratio));
+    }
+
+    fn set_token_policy(&mut self, policy: TokenPolicy) {
+        self.policy.token = policy;
+    }
+
+    fn set_spin_policy(&mut self, spin: SpinPolicy) {
+        self.policy.spin = spin;
+    }
+
+    fn set_max_waiters(&mut self, max_waiters: usize, overflow: OverflowPolicy) {
+        self.policy.max_waiters = max_waiters;
+        self.policy.overflow = overflow;
+    }
+
+    fn set_queue_discipline(&mut self, discipline: QueueDiscipline) {
+        self.discipline = discipline;
+
+        let pools = self
+            .pools
+            .lock()
+            .expect("the keyed pools are corrupted ...");
+        pools
+            .0
+            .values()
+            .for_each(|p| p.pool.set_discipline(discipline));
+    }
+
+    fn set_priority_aging(&mut self, aging: Option<Duration>) {
+        self.aging = aging;
+
+        let pools = self
+            .pools
+            .lock()
+            .expect("the keyed pools are corrupted ...");
+        pools.0.values().for_each(|p| p.pool.set_aging(aging));
+    }
+}
+
+impl<K> Drop for KeyedGateKeeper<K>
+where
+    K: Hash + Eq,
+{
+    fn drop(&mut self) {
+        self.close();
+    }
+}
@@ -49,19 +49,30 @@
 
 #![allow(deprecated)]
 
+mod clock;
 mod controller;
 mod enter;
 mod gatekeeper;
+mod gradient;
 mod inner;
+mod keyed;
 mod pass;
 mod threads_queue;
+mod timer;
+mod window;
 
+pub use clock::{Alarm, Clock, MockClock, SystemClock};
+pub use controller::Controller;
 pub use gatekeeper::{GateKeeper, GateKeeperConfig};
+pub use keyed::KeyedGateKeeper;
 pub use pass::Permit;
 use std::time::Duration;
 
 pub mod prelude {
+    pub use crate::clock::{Clock, MockClock, SystemClock};
+    pub use crate::controller::Controller;
     pub use crate::gatekeeper::{GateKeeper, GateKeeperConfig};
+    pub use crate::keyed::KeyedGateKeeper;
     //    pub use crate::pass::Token;
 }
 
@@ -73,21 +84,193 @@ pub enum RatioType {
     /// A fixed number of tokens that will become available at the beginning of every millisecond.
     /// Note that this number will *not* count or include the ones not yet returned by the holders.
     FixedRate(usize, Duration),
+
+    /// A bucket holding at most `capacity` tokens, which is refilled continuously at the pace of
+    /// `refill` tokens per `per` duration. Unused tokens are kept in the bucket (up to the
+    /// `capacity`), such that a burst is allowed after an idle period.
+    TokenBucket {
+        capacity: usize,
+        refill: usize,
+        per: Duration,
+    },
+
+    /// Admit `rate` tokens per `per` duration, with up to `burst` of them allowed to go through
+    /// back-to-back, following the Generic Cell Rate Algorithm. Admission is decided upon request
+    /// by the theoretical arrival time of the next token, hence no token generator is needed.
+    Gcra {
+        rate: usize,
+        per: Duration,
+        burst: usize,
+    },
+
+    /// Admit at most the given number of tokens within any period of the given duration, by
+    /// keeping a log of when each of the tokens in the current window was admitted.
+    SlidingWindowLog(usize, Duration),
+
+    /// Admit at most the given number of tokens within any period of the given duration, by
+    /// estimating the count in the sliding window from the counts of the current and the previous
+    /// fixed windows. It takes constant memory, at the cost of being an approximation.
+    SlidingWindowCounter(usize, Duration),
+
+    /// Admit one token every `per / count`, such that the guarded futures are paced out evenly
+    /// instead of going through in bursts. If `max_delay` is set, a future that would have to wait
+    /// in line for longer than that is rejected: an interruptable future (e.g. one issued by
+    /// `issue_with_timeout`) resolves to `Err(InterruptedReason::DelayExceeded)` when it would get
+    /// in line, while `issue` returns `None` if the line is already that long when it's called.
+    Pacing {
+        count: usize,
+        per: Duration,
+        max_delay: Option<Duration>,
+    },
+
+    /// A cap of `in_flight` tokens available at any given time, like the `Static` flavor, while at
+    /// most `count` tokens can be admitted within any period of `per` duration. A token is only
+    /// admitted if both conditions are met, and only the in-flight token is returned by the holder.
+    Composite {
+        in_flight: usize,
+        count: usize,
+        per: Duration,
+    },
+
+    /// A cap of the tokens available at any given time, like the `Static` flavor, which adapts to
+    /// the outcomes of the futures issued by `GateKeeper::issue_fallible`: starting from `initial`,
+    /// the cap grows by one on every success, and is multiplied by `backoff` on every failure,
+    /// while staying within `min` and `max`.
+    Aimd {
+        initial: usize,
+        min: usize,
+        max: usize,
+        backoff: f64,
+    },
+
+    /// A cap of the tokens available at any given time, like the `Static` flavor, which adapts to
+    /// the latency of the guarded futures: the time from a future being admitted till it returns
+    /// the token is compared against its long-term average, and the cap shrinks as the latency
+    /// grows, i.e. when the futures start to queue up on the other end, or grows otherwise. The
+    /// average follows a lasting change of the latency, such that the cap won't be pinned at
+    /// `min` after it. The cap starts from `initial` and stays within `min` and `max`.
+    Gradient {
+        initial: usize,
+        min: usize,
+        max: usize,
+    },
 }
 
 impl RatioType {
     pub(crate) fn is_static_ratio(&self) -> bool {
-        if let RatioType::Static(_) = self {
-            return true;
+        self.in_flight_cap().is_some()
+    }
+
+    pub(crate) fn in_flight_cap(&self) -> Option<usize> {
+        match self {
+            RatioType::Static(size) => Some(*size),
+            RatioType::Composite { in_flight, .. } => Some(*in_flight),
+            RatioType::Aimd { initial, .. } | RatioType::Gradient { initial, .. } => {
+                Some(*initial)
+            }
+            _ => None,
+        }
+    }
+
+    pub(crate) fn initial_tokens(&self) -> usize {
+        match *self {
+            RatioType::FixedRate(count, _) => count,
+            RatioType::TokenBucket { capacity, .. } => capacity,
+            _ => self.in_flight_cap().unwrap_or(0),
+        }
+    }
+
+    pub(crate) fn is_counted(&self) -> bool {
+        self.is_static_ratio() || self.is_refilled()
+    }
+
+    pub(crate) fn is_arrival_based(&self) -> bool {
+        matches!(self, RatioType::Gcra { .. } | RatioType::Pacing { .. })
+    }
+
+    /// The most tokens a single future can ask for, such that it can ever be admitted.
+    pub(crate) fn max_cost(&self) -> usize {
+        match *self {
+            RatioType::Static(size) => size,
+            RatioType::FixedRate(count, _) => count,
+            RatioType::TokenBucket { capacity, .. } => capacity,
+            RatioType::Gcra { rate, per, .. }
+            | RatioType::Pacing {
+                count: rate, per, ..
+            } => inner::gcra_max_cost(rate, per),
+            RatioType::SlidingWindowLog(count, _) | RatioType::SlidingWindowCounter(count, _) => {
+                count
+            }
+            RatioType::Composite {
+                in_flight, count, ..
+            } => in_flight.min(count),
+            RatioType::Aimd { min, .. } | RatioType::Gradient { min, .. } => min,
+        }
+    }
+
+    /// Check the parameters of the flavor the same way the constructors of the gatekeeper do, such
+    /// that a flavor passed in directly can't stall the pool or divide by zero later on.
+    pub(crate) fn is_valid(&self) -> bool {
+        let zero = Duration::from_nanos(0);
+
+        match *self {
+            RatioType::Static(size) => size > 0,
+            RatioType::FixedRate(count, _) => count > 0,
+            RatioType::TokenBucket {
+                capacity,
+                refill,
+                per,
+            } => capacity > 0 && refill > 0 && per > zero,
+            RatioType::Gcra { rate, per, burst } => rate > 0 && burst > 0 && per > zero,
+            RatioType::SlidingWindowLog(count, window)
+            | RatioType::SlidingWindowCounter(count, window) => count > 0 && window > zero,
+            RatioType::Pacing { count, per, .. } => count > 0 && per > zero,
+            RatioType::Composite {
+                in_flight,
+                count,
+                per,
+            } => in_flight > 0 && count > 0 && per > zero,
+            RatioType::Aimd {
+                initial,
+                min,
+                max,
+                backoff,
+            } => min > 0 && min <= initial && initial <= max && backoff > 0. && backoff < 1.,
+            RatioType::Gradient { initial, min, max } => {
+                min > 0 && min <= initial && initial <= max
+            }
         }
+    }
 
-        false
+    /// The time between two refills of the flavors refilled on schedule, i.e. the interval of a
+    /// fixed rate, or the time to earn a token of a bucket.
+    pub(crate) fn refill_period(&self) -> Option<Duration> {
+        match *self {
+            RatioType::FixedRate(_, d) => Some(d),
+            RatioType::TokenBucket { refill, per, .. } => {
+                let pace = per.as_nanos() / refill as u128;
+                Some(Duration::from_nanos(pace.max(1) as u64))
+            }
+            _ => None,
+        }
+    }
+
+    pub(crate) fn is_refilled(&self) -> bool {
+        matches!(
+            self,
+            RatioType::FixedRate(_, _) | RatioType::TokenBucket { .. }
+        )
     }
 }
 
 #[derive(Debug, PartialOrd, PartialEq)]
 pub enum InterruptedReason {
     Cancelled,
+    DelayExceeded,
+    TimedOut,
+    DeadlineExceeded,
+    QueueFull,
+    Overloaded,
 }
 
 pub enum TokenPolicy {
@@ -100,11 +283,63 @@ pub enum TokenPolicy {
     Cooperative,
 }
 
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum QueueDiscipline {
+    /// The waiting futures are woken up in the order of their priorities, then the order they
+    /// arrived, once the tokens are available, yet a newly arriving future may take the tokens
+    /// before the woken ones get to them, unless a future issued with a priority or for a tenant
+    /// is waiting, in which case the line is served like `Fifo`.
+    Relaxed,
+
+    /// The tokens are handed directly to the waiting futures in the order of their priorities, then
+    /// the order they arrived, and a newly arriving future has to wait in line as long as anyone is
+    /// waiting, such that no one can be overtaken by a future of the same or a lower priority.
+    Fifo,
+
+    /// Like `Fifo`, except that the most recently arrived future is served first among those of
+    /// the same priority, such that under a sustained overload the fresh futures are still served
+    /// in time, while the stale ones wait (and time out) instead.
+    Lifo,
+
+    /// Like `Fifo`, until the line is congested, i.e. a future has waited in line for longer than
+    /// `congested`, from which point on the line is served like `Lifo` until it's cleared up.
+    AdaptiveLifo { congested: Duration },
+
+    /// Like `Fifo`, while the line is shed once it stands: if the futures served have all waited
+    /// for longer than `target` throughout an `interval`, the waiting futures that have waited
+    /// for longer than `target` are dropped with `InterruptedReason::Overloaded`. Only the futures
+    /// that can be interrupted (e.g. by `GateKeeper::issue_with_timeout`) are dropped.
+    CoDel { target: Duration, interval: Duration },
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum OverflowPolicy {
+    /// Reject the future once the line is full.
+    Reject,
+
+    /// Make room for an interruptable future getting in line by evicting the oldest one in line
+    /// that can report the eviction, i.e. one issued by `issue_interruptable`, `issue_with_timeout`
+    /// or `issue_with_deadline`, which will then resolve to `Err(InterruptedReason::QueueFull)`. If
+    /// no such future is in line, the future getting in line is rejected. A future issued by
+    /// `issue` never evicts anyone, it's turned away upon issuance once the line is full.
+    EvictOldest,
+}
+
 #[derive(Copy, Clone, PartialEq)]
 pub enum SpinPolicy {
+    /// A cooperative future tries for the tokens again as soon as it's woken up.
     None,
 
+    /// A cooperative future that keeps returning `Poll::Pending` backs off before trying for the
+    /// tokens again, by a delay growing exponentially with each pending poll (from 16us up to
+    /// ~1ms), after which it's woken up by the clock of the gatekeeper. The thread is free to poll
+    /// other futures in the meantime. With the `SystemClock`, the delay is rounded up to the 10us
+    /// resolution of its timer; with a `MockClock`, the future only resumes once the clock has
+    /// been advanced past its backoff.
     InplaceWait,
 
+    /// A cooperative future that keeps returning `Poll::Pending` yields to the executor once
+    /// before trying for the tokens again, i.e. it's woken up right away and goes to the back of
+    /// the executor's queue, such that the other futures get their turn first.
     Yield,
 }
\ No newline at end of file
@@ -1,18 +1,23 @@
+use crate::controller::Signal;
 use crate::inner::{InnerPool, TokenFetcher};
-use crate::{InterruptedReason, SpinPolicy};
+use crate::threads_queue::{Rank, Seat};
+use crate::{InterruptedReason, OverflowPolicy, SpinPolicy};
 use std::cell::RefCell;
 use std::collections::HashSet;
 use std::future::Future;
+use std::mem;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::task::{Context, Poll};
-use std::thread;
 use std::time::Duration;
 
 thread_local!(
     static PERMIT_SET: RefCell<HashSet<usize>> = RefCell::new(HashSet::new());
 );
 
+static TICKET_ID: AtomicUsize = AtomicUsize::new(1);
+
 pub(crate) enum Envelope<R>
 where
     R: Send + 'static,
@@ -40,6 +45,9 @@ where
 {
     fut: F,
     pool: Arc<InnerPool>,
+    id: usize,
+    queued: bool,
+    seat: Arc<Seat>,
 }
 
 impl<R, F> Permit<R, F>
@@ -48,7 +56,15 @@ where
     F: Future<Output = R> + 'static,
 {
     pub(crate) fn new(fut: F, pool: Arc<InnerPool>) -> Self {
-        Permit { fut, pool }
+        let id = TICKET_ID.fetch_add(1, Ordering::Relaxed);
+
+        Permit {
+            fut,
+            pool,
+            id,
+            queued: false,
+            seat: Arc::new(Seat::new(id, Rank::default(), None)),
+        }
     }
 }
 
@@ -61,10 +77,15 @@ where
 
     fn poll(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
         // dissolve the pin to get inner contents
-        let (fut, pool) = unsafe {
+        let (fut, pool, queued, seat) = unsafe {
             let ptr = Pin::get_unchecked_mut(self);
 
-            (Pin::new_unchecked(&mut ptr.fut), &ptr.pool)
+            (
+                Pin::new_unchecked(&mut ptr.fut),
+                &ptr.pool,
+                &mut ptr.queued,
+                &ptr.seat,
+            )
         };
 
         // the current pool's id, to identify the gatekeeper
@@ -73,9 +94,18 @@ where
         // check if the parent future has already obtained the permit
         let need_token = PERMIT_SET.with(|set| !set.borrow().contains(&pool_id));
 
-        // if we're the first future to try the gatekeeper, wait for a permit to be available
+        // if we're the first future to try the gatekeeper, wait in line for a permit to be
+        // available, rather than blocking the thread till then.
         if need_token {
-            pool.request_token(false);
+            seat.take_wake();
+
+            if !take_tokens(pool, seat, 1, *queued) {
+                seat.register(ctx.waker());
+                pool.enqueue(seat, 1);
+                *queued = true;
+
+                return Poll::Pending;
+            }
 
             PERMIT_SET.with(|set| {
                 (*set.borrow_mut()).insert(pool_id);
@@ -91,24 +121,97 @@ where
                 (*set.borrow_mut()).remove(&pool_id);
             });
 
-            pool.return_token();
+            pool.return_token(1);
         }
 
         res
     }
 }
 
+impl<R, F> Drop for Permit<R, F>
+where
+    R: Send + 'static,
+    F: Future<Output = R> + 'static,
+{
+    fn drop(&mut self) {
+        // the token is returned by the end of each poll, we only need to leave the line, and pass
+        // on the tokens or the wake-up that came our way while waiting.
+        if self.queued {
+            self.pool.remove_waiter(self.id);
+        }
+
+        if self.seat.take() {
+            self.pool.hand_back(1);
+        } else if self.seat.take_wake() {
+            self.pool.pass_on();
+        }
+
+        self.pool.release_claim(self.id);
+    }
+}
+
+/// Take the tokens of the given cost for the seat, either the ones handed to it while waiting in
+/// line, or the ones available from the pool right now; this never blocks.
+fn take_tokens(pool: &InnerPool, seat: &Seat, cost: usize, queued: bool) -> bool {
+    // the tokens may have been handed to us while waiting in line, leave the line first, such that
+    // no more tokens will be handed to us.
+    let granted = seat.is_granted() && {
+        pool.remove_waiter(seat.ticket());
+        seat.take()
+    };
+
+    if granted {
+        return true;
+    }
+
+    if !pool.request_token(seat.ticket(), cost) {
+        return false;
+    }
+
+    if queued {
+        // we're done waiting, don't take up a place in line any more, nor the tokens handed to us
+        // in the meantime.
+        pool.remove_waiter(seat.ticket());
+
+        if seat.take() {
+            pool.hand_back(cost);
+        }
+    }
+
+    true
+}
+
+/// How a cooperative ticket backs off before trying for the tokens again.
+enum Backoff {
+    /// Yield to the executor once.
+    Yield,
+
+    /// Wait till the given moment of the pool's clock, and if the alarm has been set to wake us
+    /// up then.
+    Until(Duration, bool),
+}
+
 pub(crate) struct Ticket<R, F>
 where
     R: Send + 'static,
     F: Future<Output = R> + 'static,
 {
+    id: usize,
+    cost: usize,
     pool_id: usize,
     pending_count: usize,
     token_obtained: bool,
+    admitted: bool,
+    queued: bool,
+    reserved: bool,
+    max_waiters: usize,
+    overflow: OverflowPolicy,
     pool: Option<Arc<InnerPool>>,
     fut: Option<Pin<Box<F>>>,
     spin_policy: SpinPolicy,
+    backoff: Option<Backoff>,
+    signal: Option<Arc<Signal>>,
+    seat: Arc<Seat>,
 }
 
 impl<R, F> Ticket<R, F>
@@ -117,13 +220,25 @@ where
     F: Future<Output = R> + 'static,
 {
     pub(crate) fn new(pool: Arc<InnerPool>, fut: Option<F>) -> Self {
+        let id = TICKET_ID.fetch_add(1, Ordering::Relaxed);
+
         Ticket {
+            id,
+            cost: 1,
             pool_id: 0,
             pending_count: 0,
             token_obtained: false,
+            admitted: false,
+            queued: false,
+            reserved: false,
+            max_waiters: usize::MAX,
+            overflow: OverflowPolicy::Reject,
             pool: Some(pool),
             fut: fut.map(Box::pin),
             spin_policy: SpinPolicy::InplaceWait,
+            backoff: None,
+            signal: None,
+            seat: Arc::new(Seat::new(id, Rank::default(), None)),
         }
     }
 
@@ -131,14 +246,110 @@ where
         self.spin_policy = spin;
     }
 
+    pub(crate) fn set_cost(&mut self, cost: usize) {
+        self.cost = cost;
+    }
+
+    pub(crate) fn set_signal(&mut self, signal: Arc<Signal>) {
+        // the line shall be able to evict us with the signal.
+        let rank = self.seat.rank();
+        self.seat = Arc::new(Seat::new(self.id, rank, Some(Arc::clone(&signal))));
+        self.signal.replace(signal);
+    }
+
+    pub(crate) fn set_rank(&mut self, rank: Rank) {
+        self.seat = Arc::new(Seat::new(self.id, rank, self.signal.clone()));
+    }
+
+    /// Bound the line this (interruptable) ticket is about to get in, which is checked when it
+    /// first gets in line.
+    pub(crate) fn set_max_waiters(&mut self, max_waiters: usize, overflow: OverflowPolicy) {
+        self.max_waiters = max_waiters;
+        self.overflow = overflow;
+    }
+
+    /// Take over the place the pool has reserved in line for us, such that it will be released
+    /// once we're in line, admitted, or dropped.
+    pub(crate) fn hold_place(&mut self) {
+        self.reserved = true;
+    }
+
+    /// Take the tokens right away upon issuance if they're available and no one is waiting for
+    /// them, such that the ticket needs no place in line. Return false if the ticket shall wait
+    /// for the tokens once polled instead.
+    pub(crate) fn admit_now(&mut self) -> bool {
+        // not waiting in line yet, so we won't stake a claim on the tokens either.
+        self.admitted = self
+            .pool
+            .as_ref()
+            .is_some_and(|pool| pool.request_token(0, self.cost));
+
+        self.token_obtained = self.admitted;
+        self.admitted
+    }
+
+    /// Claim the tokens taken upon issuance, which is only done on the first poll.
+    fn take_admitted(&mut self) -> bool {
+        if !mem::take(&mut self.admitted) {
+            return false;
+        }
+
+        if let Some(pool) = self.pool.as_ref() {
+            let pool_id = pool.get_id();
+
+            PERMIT_SET.with(|set| {
+                set.borrow_mut().insert(pool_id);
+            });
+
+            self.pool_id = pool_id;
+        }
+
+        true
+    }
+
+    fn release_place(&mut self) {
+        if let Some(pool) = self.pool.as_ref().filter(|_| self.reserved) {
+            pool.release_place();
+        }
+
+        self.reserved = false;
+    }
+
+    /// Let the signal know we've got the token, and if we've been interrupted in the meantime,
+    /// return the token and the reason.
+    fn admit(&mut self) -> Option<InterruptedReason> {
+        let signal = self.signal.as_ref()?;
+
+        if signal.admit() {
+            return None;
+        }
+
+        let reason = signal.interrupted();
+
+        // we never got to use the token, pass it on to the next one in line.
+        if self.token_obtained {
+            if let Some(pool) = self.pool.as_ref() {
+                pool.hand_back(self.cost);
+            }
+
+            PERMIT_SET.with(|set| {
+                set.borrow_mut().remove(&self.pool_id);
+            });
+
+            self.token_obtained = false;
+        }
+
+        reason
+    }
+
     fn request_token(&mut self) -> bool {
         assert!(
             !self.token_obtained,
             "failed to return previously obtained token ... "
         );
 
-        if let Some(pool) = self.pool.as_mut() {
-            if pool.request_token(true) {
+        if let Some(pool) = self.pool.as_ref() {
+            if take_tokens(pool, &self.seat, self.cost, self.queued) {
                 let pool_id = pool.get_id();
 
                 PERMIT_SET.with(|set| {
@@ -147,6 +358,7 @@ where
 
                 self.pool_id = pool_id;
                 self.token_obtained = true;
+                self.release_place();
 
                 return true;
             }
@@ -155,15 +367,53 @@ where
         false
     }
 
+    /// Hold off the attempt for the tokens, and return false if the backoff is already over. We
+    /// shall be woken up once it's over, rather than blocking the thread till then.
+    fn back_off(&mut self, backoff: Backoff, ctx: &Context<'_>) -> bool {
+        let (at, armed) = match backoff {
+            Backoff::Yield => {
+                ctx.waker().wake_by_ref();
+                return true;
+            }
+            Backoff::Until(at, armed) => (at, armed),
+        };
+
+        let clock = match self.pool.as_ref() {
+            Some(pool) => pool.clock(),
+            None => return false,
+        };
+
+        if clock.now() >= at {
+            return false;
+        }
+
+        // the alarm is only set once, and wakes us up with the waker we're last polled with.
+        self.seat.register(ctx.waker());
+
+        if !armed {
+            let seat = Arc::clone(&self.seat);
+            clock.schedule(
+                at,
+                Box::new(move || {
+                    if let Some(waker) = seat.waker() {
+                        waker.wake();
+                    }
+                }),
+            );
+        }
+
+        // we may be polled again before then, keep backing off till it's over.
+        self.backoff.replace(Backoff::Until(at, true));
+        true
+    }
+
     fn make_stub(&mut self) -> TicketStub {
         // now the token has been transferred to the `stub`, we no longer own the token, and we
         // won't need to render the token from the drop function.
         self.token_obtained = false;
 
         // generate the stub from the ticket
-        TicketStub {
-            pool: self.pool.take().unwrap(),
-        }
+        TicketStub::new(self.pool.take().unwrap(), self.cost)
     }
 }
 
@@ -181,7 +431,7 @@ where
                 "failed at double-returning a previously obtained token ... "
             );
 
-            pool.return_token();
+            pool.return_token(self.cost);
 
             PERMIT_SET.with(|set| {
                 set.borrow_mut().remove(&self.pool_id);
@@ -198,7 +448,29 @@ where
     F: Future<Output = R> + 'static,
 {
     fn drop(&mut self) {
-        self.render_token();
+        // we may never have obtained a token, i.e. dropped while waiting in line or rejected.
+        if self.token_obtained {
+            self.render_token();
+        }
+
+        if let Some(pool) = self.pool.as_ref() {
+            // leave the line, and pass on the tokens handed to us, or the wake-up we've received
+            // for the tokens, while we were waiting.
+            if self.queued {
+                pool.remove_waiter(self.id);
+            }
+
+            if self.seat.take() {
+                pool.hand_back(self.cost);
+            } else if self.seat.take_wake() {
+                pool.pass_on();
+            }
+
+            // if we've been waiting for the tokens to pile up, don't hold up the line any more.
+            pool.release_claim(self.id);
+        }
+
+        self.release_place();
     }
 }
 
@@ -215,11 +487,36 @@ where
             "The pass has already been issued, yet the ticket is polled for access again ... "
         );
 
-        //TODO: check remote_control, if interrupted, return
-        //       Poll::Ready(Err(InterruptedReason::Cancelled));
-
         let ref_this = self.get_mut();
 
+        // if we've been cancelled or timed out, leave the line for good.
+        if let Some(signal) = ref_this.signal.as_ref() {
+            signal.register(ctx.waker());
+
+            if let Some(reason) = signal.interrupted() {
+                if let Some(pool) = ref_this.pool.as_ref() {
+                    pool.remove_waiter(ref_this.id);
+
+                    // the wake-up we've received for the tokens shall go to the next one in line.
+                    if ref_this.seat.take_wake() {
+                        pool.pass_on();
+                    }
+                }
+
+                return Poll::Ready(Err(reason));
+            }
+        }
+
+        // we're acting on the wake-up from the line, if any, right now.
+        ref_this.seat.take_wake();
+
+        // back off before trying for the tokens again, the thread is free to poll the others.
+        if let Some(backoff) = ref_this.backoff.take() {
+            if ref_this.back_off(backoff, ctx) {
+                return Poll::Pending;
+            }
+        }
+
         // check if the parent future has already obtained the permit
         let need_token = if ref_this.pool_id == 0 {
             true
@@ -227,7 +524,13 @@ where
             PERMIT_SET.with(|set| !set.borrow().contains(&ref_this.pool_id))
         };
 
-        if !need_token || ref_this.request_token() {
+        if !need_token || ref_this.take_admitted() || ref_this.request_token() {
+            // we may have been interrupted right before taking the token, then we shall not keep
+            // it.
+            if let Some(reason) = ref_this.admit() {
+                return Poll::Ready(Err(reason));
+            }
+
             // if we own the future (i.e. we're in the `TicketStubPolicy::Cooperative` mode), poll
             // the future and return from what we got. Either way, the TicketStub will be returned
             // afterwards -- either explicitly when results are pending, or implicitly when the
@@ -251,11 +554,16 @@ where
                         }
 
                         if ref_this.pending_count >= 4 && spin {
-                            if ref_this.spin_policy == SpinPolicy::Yield {
-                                thread::yield_now();
+                            let backoff = if ref_this.spin_policy == SpinPolicy::Yield {
+                                Backoff::Yield
                             } else {
-                                thread::sleep(Duration::from_micros((1 << ref_this.pending_count) as u64));
-                            }
+                                let delay = Duration::from_micros(1 << ref_this.pending_count);
+                                let now = ref_this.pool.as_ref().map(|p| p.clock().now());
+
+                                Backoff::Until(now.unwrap_or_default() + delay, false)
+                            };
+
+                            ref_this.backoff.replace(backoff);
 
                             if ref_this.pending_count >= 10 {
                                 ref_this.pending_count = 4;
@@ -282,22 +590,69 @@ where
 
         // we can't get a token yet, make sure correct context are set, then we will go back to wait.
         if let Some(pool) = ref_this.pool.as_ref() {
+            // before we get in line for the first time, make sure the pool would take us. Only an
+            // interruptable ticket can be turned away now, the others have been checked upon
+            // issuance.
+            if !ref_this.queued {
+                if ref_this.signal.is_some() {
+                    let room = pool
+                        .can_wait()
+                        .and_then(|_| pool.make_room(ref_this.max_waiters, ref_this.overflow));
+
+                    if let Err(reason) = room {
+                        return Poll::Ready(Err(reason));
+                    }
+                }
+
+                ref_this.queued = true;
+            }
+
             // only enqueue to wake up if we're in the preemptive mode; otherwise the owning future
-            // will wake us up
-            pool.enqueue(ctx.waker().clone());
+            // will wake us up. The seat keeps its place in line if we're polled again, only the
+            // waker is updated.
+            ref_this.seat.register(ctx.waker());
+            pool.enqueue(&ref_this.seat, ref_this.cost);
         }
 
+        // we're in line now, and counted as such.
+        ref_this.release_place();
+
         Poll::Pending
     }
 }
 
 pub(crate) struct TicketStub {
     pool: Arc<InnerPool>,
+    cost: usize,
+    issued_at: Option<u64>,
+}
+
+impl TicketStub {
+    /// Hold on to the tokens of the given cost, which have already been taken from the pool.
+    pub(crate) fn new(pool: Arc<InnerPool>, cost: usize) -> Self {
+        let mut stub = TicketStub::unstarted(pool, cost);
+        stub.start();
+        stub
+    }
+
+    /// Hold on to the tokens for a future that hasn't started yet, whose round-trip time is only
+    /// measured from `start` on.
+    pub(crate) fn unstarted(pool: Arc<InnerPool>, cost: usize) -> Self {
+        TicketStub {
+            pool,
+            cost,
+            issued_at: None,
+        }
+    }
+
+    pub(crate) fn start(&mut self) {
+        self.issued_at = Some(self.pool.elapsed());
+    }
 }
 
 impl TokenHolder for TicketStub {
     fn render_token(&mut self) {
-        self.pool.return_token();
+        self.pool.return_token(self.cost);
     }
 }
 
@@ -305,6 +660,10 @@ impl Drop for TicketStub {
     fn drop(&mut self) {
         // if we still own the reference to the poll, it means we need to return the TicketStub to the
         // pool. A Lannister never forgets his or her debts!
+        if let Some(issued_at) = self.issued_at {
+            self.pool.on_rtt(self.pool.elapsed().saturating_sub(issued_at));
+        }
+
         self.render_token();
     }
 }
@@ -1,5 +1,12 @@
-use crossbeam_queue::SegQueue;
+use crate::clock::Clock;
+use crate::controller::Signal;
+use crate::QueueDiscipline;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::task::Waker;
+use std::time::Duration;
 
 /*
 use std::thread::Thread;
@@ -21,19 +28,493 @@ impl ThreadsQueue {
 }
 */
 
-pub(crate) struct WaitingList(SegQueue<Waker>);
+/// The virtual time it takes to serve a token for a tenant of weight `1`.
+const FAIR_SHARE: u128 = 1 << 20;
+
+/// How a ticket is ranked against the others in line: a ticket of a higher priority is always
+/// served first, and the tickets of the same priority share the line among their tenants by the
+/// weights of the tenants.
+#[derive(Clone, Copy)]
+pub(crate) struct Rank {
+    pub(crate) priority: usize,
+    pub(crate) tenant: usize,
+    pub(crate) weight: usize,
+}
+
+impl Rank {
+    /// Check if the ticket shall be served ahead of the others, or take turns with the others by
+    /// its tenant, which it can only be if no one can barge in before it.
+    fn is_ranked(&self) -> bool {
+        self.priority > 0 || self.tenant != 0 || self.weight != 1
+    }
+}
+
+impl Default for Rank {
+    fn default() -> Self {
+        Rank {
+            priority: 0,
+            tenant: 0,
+            weight: 1,
+        }
+    }
+}
+
+/// The seat of a ticket in line, shared by the ticket and the lines it's waiting in, such that the
+/// line can hand the tokens to the ticket directly, and the ticket can find out if it's been woken
+/// up by the line. The seat holds the one waker to wake the ticket up with, however many times the
+/// ticket has been polled.
+pub(crate) struct Seat {
+    ticket: usize,
+    rank: Rank,
+    signal: Option<Arc<Signal>>,
+    waker: Mutex<Option<Waker>>,
+    granted: AtomicBool,
+    woken: AtomicBool,
+}
+
+impl Seat {
+    pub(crate) fn new(ticket: usize, rank: Rank, signal: Option<Arc<Signal>>) -> Self {
+        Seat {
+            ticket,
+            rank,
+            signal,
+            waker: Mutex::new(None),
+            granted: AtomicBool::new(false),
+            woken: AtomicBool::new(false),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn ticket(&self) -> usize {
+        self.ticket
+    }
+
+    #[inline]
+    pub(crate) fn rank(&self) -> Rank {
+        self.rank
+    }
+
+    /// Update the waker to wake the ticket up with, unless it would wake up the same task anyway.
+    pub(crate) fn register(&self, waker: &Waker) {
+        let mut slot = self.waker.lock().expect("the seat is corrupted ...");
+
+        if !slot.as_ref().is_some_and(|w| w.will_wake(waker)) {
+            slot.replace(waker.clone());
+        }
+    }
+
+    pub(crate) fn waker(&self) -> Option<Waker> {
+        self.waker.lock().expect("the seat is corrupted ...").clone()
+    }
+
+    #[inline]
+    pub(crate) fn is_granted(&self) -> bool {
+        self.granted.load(Ordering::Acquire)
+    }
+
+    /// Take the tokens handed to the ticket, and return if there were any.
+    pub(crate) fn take(&self) -> bool {
+        self.granted.swap(false, Ordering::AcqRel)
+    }
+
+    /// Take the wake-up the ticket has received from the line, and return if there was one.
+    pub(crate) fn take_wake(&self) -> bool {
+        self.woken.swap(false, Ordering::AcqRel)
+    }
+
+    fn grant(&self) {
+        self.granted.store(true, Ordering::Release);
+    }
+}
+
+/// The outcome of trying to take the tokens for the ticket at the head of the line.
+pub(crate) enum Grant {
+    /// The tokens have been taken for the ticket.
+    Taken,
+
+    /// The tokens are available, yet the ticket has to wait for someone else, e.g. an ancestor.
+    HeldUp,
+
+    /// The tokens are not available yet.
+    Unavailable,
+}
+
+struct Waiter {
+    seat: Arc<Seat>,
+    cost: usize,
+    grantable: bool,
+    finish: u128,
+    since: Duration,
+}
+
+impl Waiter {
+    /// The priority of the ticket, raised by one for every `aging` it has been waiting in line.
+    fn priority(&self, now: Duration, aging: Option<Duration>) -> usize {
+        let aged = aging.map_or(0, |aging| {
+            let waited = now.saturating_sub(self.since).as_nanos();
+            (waited / aging.as_nanos().max(1)) as usize
+        });
+
+        self.seat.rank.priority.saturating_add(aged)
+    }
+
+    /// Let the ticket know it's been woken up, and return the waker to wake it up with.
+    fn wake(&self) -> Option<Waker> {
+        self.seat.woken.store(true, Ordering::Release);
+        self.seat.waker()
+    }
+}
+
+/// The tickets in line, each of which shows up in the line only once. The waiters are kept in
+/// the order of their priorities, and in the order of their virtual finish times for the same
+/// priority, i.e. the tenants take turns in proportion to their weights, such that a tenant
+/// flooding the line only holds up itself (see self-clocked fair queueing).
+struct Line {
+    waiters: VecDeque<Waiter>,
+    tickets: HashSet<usize>,
+    clock: Arc<dyn Clock>,
+    aging: Option<Duration>,
+    discipline: QueueDiscipline,
+
+    /// Since when the tickets served have all waited for longer than the target of `CoDel`.
+    standing: Option<Duration>,
+
+    /// The number of tickets in line ranked ahead of the others.
+    ranked: usize,
+
+    /// The virtual finish time of the last ticket served, and of the last ticket in line of each
+    /// tenant.
+    virtual_time: u128,
+    tenants: HashMap<usize, u128>,
+}
+
+impl Line {
+    fn push(&mut self, seat: Arc<Seat>, cost: usize, grantable: bool) {
+        let rank = seat.rank;
+
+        // a tenant that has caught up with the line starts over from now.
+        let start = self
+            .tenants
+            .get(&rank.tenant)
+            .map_or(self.virtual_time, |&last| last.max(self.virtual_time));
+        let finish = start + cost as u128 * FAIR_SHARE / rank.weight.max(1) as u128;
+
+        // forget about the tenants who have caught up, such that they won't pile up.
+        if self.tenants.len() > 2 * self.waiters.len() {
+            let virtual_time = self.virtual_time;
+            self.tenants.retain(|_, last| *last > virtual_time);
+        }
+
+        self.tenants.insert(rank.tenant, finish);
+
+        if rank.is_ranked() {
+            self.ranked += 1;
+        }
+
+        let pos = self
+            .waiters
+            .iter()
+            .position(|w| {
+                let prio = w.seat.rank.priority;
+                prio < rank.priority || (prio == rank.priority && w.finish > finish)
+            })
+            .unwrap_or(self.waiters.len());
+
+        self.waiters.insert(
+            pos,
+            Waiter {
+                seat,
+                cost,
+                grantable,
+                finish,
+                since: self.clock.now(),
+            },
+        );
+    }
+
+    /// The position of the ticket to be served first: the one with the highest priority, then the
+    /// one of the earliest virtual finish time, or the one that has waited the longest among them.
+    /// In the LIFO order, the one that has arrived the latest is served first instead.
+    fn head(&self) -> Option<usize> {
+        if self.waiters.is_empty() {
+            return None;
+        }
+
+        let now = self.clock.now();
+
+        if self.is_lifo(now) {
+            return self
+                .waiters
+                .iter()
+                .enumerate()
+                .max_by_key(|(_, w)| (w.priority(now, self.aging), w.since))
+                .map(|(pos, _)| pos);
+        }
+
+        // without aging, the priorities stay as they were when the tickets got in line.
+        if self.aging.is_none() {
+            return Some(0);
+        }
+
+        self.waiters
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, w)| {
+                (
+                    w.priority(now, self.aging),
+                    Reverse(w.finish),
+                    Reverse(w.since),
+                )
+            })
+            .map(|(pos, _)| pos)
+    }
+
+    fn is_lifo(&self, now: Duration) -> bool {
+        match self.discipline {
+            QueueDiscipline::Lifo => true,
+            QueueDiscipline::AdaptiveLifo { congested } => self
+                .waiters
+                .iter()
+                .map(|w| w.since)
+                .min()
+                .is_some_and(|oldest| now.saturating_sub(oldest) > congested),
+            _ => false,
+        }
+    }
+
+    /// Drop the tickets that have waited for longer than the target of `CoDel`, once the tickets
+    /// served have all waited for longer than the target throughout the interval, i.e. the line
+    /// is standing rather than just bursting.
+    fn shed(&mut self) {
+        let (target, interval) = match self.discipline {
+            QueueDiscipline::CoDel { target, interval } => (target, interval),
+            _ => return,
+        };
+
+        let now = self.clock.now();
+        let sojourn = |w: &Waiter| now.saturating_sub(w.since);
+
+        match self.head() {
+            Some(pos) if sojourn(&self.waiters[pos]) > target => {}
+            _ => {
+                self.standing = None;
+                return;
+            }
+        }
+
+        let standing = *self.standing.get_or_insert(now);
+        if now.saturating_sub(standing) < interval {
+            return;
+        }
+
+        let mut pos = 0;
+        while pos < self.waiters.len() {
+            let waiter = &self.waiters[pos];
+
+            // the ticket may have been interrupted otherwise, which will leave the line itself.
+            if sojourn(waiter) > target && waiter.seat.signal.as_ref().is_some_and(|s| s.shed()) {
+                self.remove(pos);
+            } else {
+                pos += 1;
+            }
+        }
+    }
+
+    fn pop_head(&mut self) -> Option<Waiter> {
+        let pos = self.head()?;
+        self.serve(pos)
+    }
+
+    /// Take the ticket out of the line to be served, which moves the virtual time forward.
+    fn serve(&mut self, pos: usize) -> Option<Waiter> {
+        let waiter = self.remove(pos)?;
+        self.virtual_time = self.virtual_time.max(waiter.finish);
+
+        Some(waiter)
+    }
+
+    fn remove(&mut self, pos: usize) -> Option<Waiter> {
+        let waiter = self.waiters.remove(pos)?;
+        self.tickets.remove(&waiter.seat.ticket);
+
+        if waiter.seat.rank.is_ranked() {
+            self.ranked -= 1;
+        }
+
+        // no one is behind, the virtual time can start over.
+        if self.waiters.is_empty() {
+            self.virtual_time = 0;
+            self.tenants.clear();
+        }
+
+        Some(waiter)
+    }
+}
+
+pub(crate) struct WaitingList(Mutex<Line>);
 
 impl WaitingList {
-    pub(crate) fn new() -> Self {
-        WaitingList(SegQueue::new())
+    pub(crate) fn new(clock: Arc<dyn Clock>) -> Self {
+        WaitingList(Mutex::new(Line {
+            waiters: VecDeque::new(),
+            tickets: HashSet::new(),
+            clock,
+            aging: None,
+            discipline: QueueDiscipline::Relaxed,
+            standing: None,
+            ranked: 0,
+            virtual_time: 0,
+            tenants: HashMap::new(),
+        }))
     }
 
-    pub(crate) fn enqueue(&self, w: Waker) {
-        self.0.push(w);
+    pub(crate) fn set_discipline(&self, discipline: QueueDiscipline) {
+        let mut line = self.line();
+
+        line.discipline = discipline;
+        line.standing = None;
+    }
+
+    /// Raise the priority of the waiting tickets by one for every `aging` they have waited, such
+    /// that the tickets of low priorities won't be starved forever.
+    pub(crate) fn set_aging(&self, aging: Option<Duration>) {
+        self.line().aging = aging;
+    }
+
+    /// Get the ticket in line by its rank, unless it's already waiting in line, in which case it
+    /// keeps its place. The tokens are only handed to the ticket directly if `grantable` is set,
+    /// otherwise the ticket is woken up to take the tokens itself.
+    pub(crate) fn enqueue(&self, seat: Arc<Seat>, cost: usize, grantable: bool) {
+        let mut line = self.line();
+
+        if line.tickets.insert(seat.ticket) {
+            line.push(seat, cost, grantable);
+        }
     }
 
     pub(crate) fn dequeue(&self) -> Option<Waker> {
-        self.0.pop().ok()
+        let mut line = self.line();
+        line.shed();
+
+        // the ticket may not have registered a waker, in which case it's being polled anyway.
+        while let Some(waiter) = line.pop_head() {
+            if let Some(waker) = waiter.wake() {
+                return Some(waker);
+            }
+        }
+
+        None
+    }
+
+    /// Take the ticket out of the line; if it's been at the head, it's leaving with the tokens.
+    pub(crate) fn remove(&self, ticket: usize) {
+        let mut line = self.line();
+
+        if !line.tickets.contains(&ticket) {
+            return;
+        }
+
+        if let Some(pos) = line.waiters.iter().position(|w| w.seat.ticket == ticket) {
+            if line.head() == Some(pos) {
+                line.serve(pos);
+            } else {
+                line.remove(pos);
+            }
+        }
+    }
+
+    /// Hand the tokens to the tickets at the head of the line one after another, for as long as
+    /// `take` can take the tokens for them, and return the wakers of the tickets to wake up.
+    pub(crate) fn hand_off<T>(&self, mut take: T) -> Vec<Waker>
+    where
+        T: FnMut(usize, usize) -> Grant,
+    {
+        let mut line = self.line();
+        let mut woken = Vec::new();
+
+        line.shed();
+
+        while let Some(pos) = line.head() {
+            // the deadline has passed while the ticket is waiting, the tokens go to the next one
+            // in line instead, and the ticket is woken up to find out.
+            let seat = &line.waiters[pos].seat;
+            if seat.signal.as_ref().is_some_and(|s| s.is_expired()) {
+                woken.extend(line.remove(pos).and_then(|w| w.seat.waker()));
+                continue;
+            }
+
+            let head = &line.waiters[pos];
+
+            // the ticket can't take the tokens from us, let it try itself; it keeps its place at
+            // the head of the line until it leaves.
+            if !head.grantable {
+                woken.extend(head.wake());
+                break;
+            }
+
+            match take(head.seat.ticket, head.cost) {
+                Grant::Taken => {
+                    if let Some(w) = line.serve(pos) {
+                        w.seat.grant();
+                        woken.extend(w.seat.waker());
+                    }
+                }
+                Grant::HeldUp => {
+                    woken.extend(head.seat.waker());
+                    break;
+                }
+                Grant::Unavailable => break,
+            }
+        }
+
+        woken
+    }
+
+    /// Check if the ticket is the first one to be served, or if no one is waiting at all.
+    pub(crate) fn is_head(&self, ticket: usize) -> bool {
+        let line = self.line();
+        line.head()
+            .map_or(true, |pos| line.waiters[pos].seat.ticket == ticket)
+    }
+
+    /// Evict the oldest ticket in line that can be interrupted, and return if one was evicted.
+    pub(crate) fn evict_oldest(&self) -> bool {
+        let mut line = self.line();
+
+        while let Some(pos) = line
+            .waiters
+            .iter()
+            .enumerate()
+            .filter(|(_, w)| w.seat.signal.is_some())
+            .min_by_key(|(_, w)| w.since)
+            .map(|(pos, _)| pos)
+        {
+            let waiter = line.remove(pos).expect("the waiter shall be in line ...");
+
+            // the ticket may have been interrupted otherwise, which will leave the line itself.
+            if waiter.seat.signal.as_ref().is_some_and(|s| s.evict()) {
+                return true;
+            }
+        }
+
+        false
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.line().waiters.is_empty()
+    }
+
+    /// Check if any ticket in line is ranked ahead of the others, e.g. by its priority.
+    pub(crate) fn has_ranked(&self) -> bool {
+        self.line().ranked > 0
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.line().waiters.len()
+    }
+
+    fn line(&self) -> MutexGuard<'_, Line> {
+        self.0.lock().expect("the waiting list is corrupted ...")
     }
 }
 
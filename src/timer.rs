@@ -0,0 +1,220 @@
+use crate::clock::Alarm;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Condvar, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The resolution of the timer; an alarm goes off on the first tick at or after its moment. It's
+/// kept well below a millisecond, such that the flavors admitting on request (e.g. GCRA) can be
+/// served at their rates rather than once per tick.
+const TICK: Duration = Duration::from_micros(10);
+
+/// Each level of the wheel has 64 slots, and each slot of a level spans all the slots of the
+/// level below it, i.e. the 6 levels together span 2^36 ticks (~8 days). The alarms beyond that
+/// wait on the top level till they're in reach.
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+const LEVELS: usize = 6;
+
+static TIMER: OnceLock<Timer> = OnceLock::new();
+
+struct Entry {
+    at: u64,
+    alarm: Alarm,
+}
+
+/// A hierarchical timing wheel: an alarm is placed on the lowest level whose slots can tell its
+/// tick apart from the current one, and is moved down a level each time the wheel turns onto its
+/// slot, till it's due.
+struct Wheel {
+    /// The ticks are counted from the origin, and all the alarms before `now` have gone off.
+    origin: Instant,
+    now: u64,
+    slots: Vec<Vec<Entry>>,
+    len: usize,
+    due: Vec<Alarm>,
+
+    /// The tick the timer thread is sleeping till, if it's sleeping on a deadline.
+    sleeping: Option<u64>,
+}
+
+impl Wheel {
+    fn new() -> Self {
+        Wheel {
+            origin: Instant::now(),
+            now: 0,
+            slots: (0..LEVELS * SLOTS).map(|_| Vec::new()).collect(),
+            len: 0,
+            due: Vec::new(),
+            sleeping: None,
+        }
+    }
+
+    /// The tick at or after the moment, such that no alarm goes off before its moment.
+    fn tick_of(&self, at: Instant) -> u64 {
+        let nanos = at.saturating_duration_since(self.origin).as_nanos();
+        ((nanos + TICK.as_nanos() - 1) / TICK.as_nanos()) as u64
+    }
+
+    fn moment_of(&self, tick: u64) -> Instant {
+        self.origin + Duration::from_nanos(tick.saturating_mul(TICK.as_nanos() as u64))
+    }
+
+    /// The tick that has passed by the moment.
+    fn tick_at(&self, at: Instant) -> u64 {
+        (at.saturating_duration_since(self.origin).as_nanos() / TICK.as_nanos()) as u64
+    }
+
+    fn insert(&mut self, entry: Entry) {
+        if entry.at <= self.now {
+            self.due.push(entry.alarm);
+            return;
+        }
+
+        // the highest group of bits the tick differs from the current one decides the level.
+        let level = ((63 - (entry.at ^ self.now).leading_zeros()) / SLOT_BITS) as usize;
+        let level = level.min(LEVELS - 1);
+        let slot = (entry.at >> (level as u32 * SLOT_BITS)) as usize & (SLOTS - 1);
+
+        self.slots[level * SLOTS + slot].push(entry);
+        self.len += 1;
+    }
+
+    /// Turn the wheel till the given tick, moving the alarms down the levels and collecting the
+    /// ones that are due along the way.
+    fn advance(&mut self, to: u64) {
+        // nothing to move along, jump right there.
+        if self.len == 0 {
+            self.now = self.now.max(to);
+            return;
+        }
+
+        while self.now < to {
+            // no slot holding an alarm is turned onto before the next tick, skip right there.
+            self.now = self.next_tick().map_or(to, |next| next.min(to));
+
+            // the lower levels have come around, move the alarms of the slot we've turned onto.
+            for level in 0..LEVELS {
+                let shift = level as u32 * SLOT_BITS;
+                if level > 0 && self.now & ((1 << shift) - 1) != 0 {
+                    break;
+                }
+
+                let slot = (self.now >> shift) as usize & (SLOTS - 1);
+                let entries = mem::take(&mut self.slots[level * SLOTS + slot]);
+
+                self.len -= entries.len();
+                entries.into_iter().for_each(|e| self.insert(e));
+            }
+
+            if self.len == 0 {
+                self.now = to;
+            }
+        }
+    }
+
+    /// The tick by which the wheel shall be turned next, i.e. when the earliest slot holding an
+    /// alarm is turned onto, on any level.
+    fn next_tick(&self) -> Option<u64> {
+        if self.len == 0 {
+            return None;
+        }
+
+        (0..LEVELS)
+            .filter_map(|level| {
+                let shift = level as u32 * SLOT_BITS;
+                let curr = self.now >> shift;
+
+                (1..=SLOTS as u64)
+                    .find(|i| {
+                        let slot = (curr + i) as usize & (SLOTS - 1);
+                        !self.slots[level * SLOTS + slot].is_empty()
+                    })
+                    .map(|i| (curr + i) << shift)
+            })
+            .min()
+    }
+}
+
+struct Timer {
+    wheel: Mutex<Wheel>,
+    signal: Condvar,
+}
+
+impl Timer {
+    fn run(&self) {
+        let mut wheel = self.wheel.lock().expect("the timer is corrupted ...");
+
+        loop {
+            let now = wheel.tick_at(Instant::now());
+            wheel.advance(now);
+
+            if !wheel.due.is_empty() {
+                // don't hold the lock while firing, the alarm may want to schedule a new one.
+                let due = mem::take(&mut wheel.due);
+                drop(wheel);
+
+                // nor let a panicking alarm take the timer down, the rest would never go off.
+                due.into_iter().for_each(|alarm| {
+                    let _ = panic::catch_unwind(AssertUnwindSafe(alarm));
+                });
+
+                wheel = self.wheel.lock().expect("the timer is corrupted ...");
+                continue;
+            }
+
+            wheel.sleeping = wheel.next_tick();
+
+            wheel = match wheel.sleeping {
+                Some(at) => {
+                    let timeout = wheel
+                        .moment_of(at)
+                        .saturating_duration_since(Instant::now());
+
+                    self.signal
+                        .wait_timeout(wheel, timeout)
+                        .expect("the timer is corrupted ...")
+                        .0
+                }
+                None => self.signal.wait(wheel).expect("the timer is corrupted ..."),
+            };
+
+            wheel.sleeping = None;
+        }
+    }
+}
+
+/// Schedule the alarm to go off at the given moment. All alarms are served by a single timer
+/// thread, which is spawned the first time we need it.
+pub(crate) fn schedule(at: Instant, alarm: Alarm) {
+    let mut spawned = false;
+    let timer = TIMER.get_or_init(|| {
+        spawned = true;
+
+        Timer {
+            wheel: Mutex::new(Wheel::new()),
+            signal: Condvar::new(),
+        }
+    });
+
+    if spawned {
+        thread::spawn(move || timer.run());
+    }
+
+    let mut wheel = timer.wheel.lock().expect("the timer is corrupted ...");
+
+    // the wheel may have stood still while there was nothing to wait for.
+    if wheel.len == 0 {
+        let now = wheel.tick_at(Instant::now());
+        wheel.advance(now);
+    }
+
+    let at = wheel.tick_of(at);
+    wheel.insert(Entry { at, alarm });
+
+    // the timer thread may be sleeping for a later alarm, let it know.
+    if !wheel.due.is_empty() || wheel.sleeping.map_or(true, |sleeping| at < sleeping) {
+        timer.signal.notify_one();
+    }
+}
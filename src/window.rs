@@ -0,0 +1,138 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// The bookkeeping for the sliding window flavors, all moments are in nanoseconds since the pool
+/// was created.
+pub(crate) struct SlidingWindow {
+    log: VecDeque<u64>,
+    slot: u64,
+    prev: usize,
+    curr: usize,
+}
+
+impl SlidingWindow {
+    pub(crate) fn new() -> Self {
+        SlidingWindow {
+            log: VecDeque::new(),
+            slot: 0,
+            prev: 0,
+            curr: 0,
+        }
+    }
+
+    pub(crate) fn reset(&mut self) {
+        *self = SlidingWindow::new();
+    }
+
+    /// Admit the tokens of the given cost if no more than `count` tokens would have been admitted
+    /// within the last `window`, by the exact moments they were admitted. If the tokens can't be
+    /// admitted yet, return the moment when enough records in the log have left the window. The
+    /// tokens are only recorded if `take` is set.
+    pub(crate) fn check_log(
+        &mut self,
+        now: u64,
+        count: usize,
+        window: Duration,
+        cost: usize,
+        take: bool,
+    ) -> Result<(), u64> {
+        let window = window.as_nanos() as u64;
+        let cost = cost.min(count);
+
+        // drop the records that have slid out of the window
+        while let Some(&at) = self.log.front() {
+            if at + window > now {
+                break;
+            }
+
+            self.log.pop_front();
+        }
+
+        if self.log.len() + cost <= count {
+            if take {
+                self.log.extend((0..cost).map(|_| now));
+            }
+
+            return Ok(());
+        }
+
+        Err(self.log[self.log.len() + cost - count - 1] + window)
+    }
+
+    /// Take the most recent records of the given cost out of the log.
+    pub(crate) fn refund_log(&mut self, cost: usize) {
+        let len = self.log.len();
+        self.log.truncate(len.saturating_sub(cost));
+    }
+
+    /// Admit the tokens of the given cost if the estimated count within the last `window` stays
+    /// within `count`. The estimation weighs the count of the previous fixed window by the part of
+    /// it that's still covered by the sliding window, and adds the count of the current fixed
+    /// window. If the tokens can't be admitted yet, return the moment when the estimation drops
+    /// enough to admit them. The tokens are only counted if `take` is set.
+    pub(crate) fn check_counter(
+        &mut self,
+        now: u64,
+        count: usize,
+        window: Duration,
+        cost: usize,
+        take: bool,
+    ) -> Result<(), u64> {
+        let window = (window.as_nanos() as u64).max(1);
+        let cost = cost.min(count);
+        let slot = now / window;
+
+        if slot == self.slot + 1 {
+            self.prev = self.curr;
+            self.curr = 0;
+        } else if slot > self.slot + 1 {
+            self.prev = 0;
+            self.curr = 0;
+        }
+
+        self.slot = self.slot.max(slot);
+
+        let start = self.slot * window;
+        let offset = now.saturating_sub(start);
+
+        // within the current fixed window, or from the start of the next one, find out when the
+        // weighted previous count becomes small enough.
+        match earliest(self.prev, self.curr, count, cost, window) {
+            Some(at) if at <= offset => {
+                if take {
+                    self.curr += cost;
+                }
+
+                Ok(())
+            }
+            Some(at) if at < window => Err(start + at),
+            _ => {
+                let at = earliest(self.curr, 0, count, cost, window).unwrap_or(window);
+                Err(start + window + at)
+            }
+        }
+    }
+
+    /// Take the tokens of the given cost out of the count of the current fixed window.
+    pub(crate) fn refund_counter(&mut self, cost: usize) {
+        self.curr = self.curr.saturating_sub(cost);
+    }
+}
+
+/// The earliest offset into the current fixed window when the tokens of the given cost fit in.
+fn earliest(prev: usize, curr: usize, count: usize, cost: usize, window: u64) -> Option<u64> {
+    if curr + cost > count {
+        return None;
+    }
+
+    let room = (count - curr - cost) as u128 * u128::from(window);
+    let weighted = prev as u128 * u128::from(window);
+
+    if weighted <= room {
+        return Some(0);
+    }
+
+    // prev * (window - offset) <= room, hence offset >= window - room / prev; round it up.
+    let offset = (weighted - room + prev as u128 - 1) / prev as u128;
+    Some(offset as u64)
+}
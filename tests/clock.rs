@@ -0,0 +1,58 @@
+use futures_rate::{Clock, MockClock};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+fn ms(millis: u64) -> Duration {
+    Duration::from_millis(millis)
+}
+
+/// Schedule an alarm recording the time it goes off at.
+fn record(clock: &Arc<MockClock>, fired: &Arc<Mutex<Vec<Duration>>>, at: Duration) {
+    let (c, fired) = (Arc::clone(clock), Arc::clone(fired));
+    clock.schedule(at, Box::new(move || fired.lock().unwrap().push(c.now())));
+}
+
+#[test]
+fn alarms_go_off_in_order_at_their_moments() {
+    let clock = Arc::new(MockClock::new());
+    let fired = Arc::new(Mutex::new(Vec::new()));
+
+    for at in [30, 10, 20] {
+        record(&clock, &fired, ms(at));
+    }
+
+    // nothing goes off till the clock is moved.
+    assert!(fired.lock().unwrap().is_empty());
+    assert_eq!(clock.pending(), 3);
+
+    clock.advance(ms(25));
+    assert_eq!(*fired.lock().unwrap(), vec![ms(10), ms(20)]);
+    assert_eq!(clock.now(), ms(25));
+    assert_eq!(clock.pending(), 1);
+}
+
+#[test]
+fn alarms_scheduled_by_an_alarm_go_off_if_due_by_then() {
+    let clock = Arc::new(MockClock::new());
+    let fired = Arc::new(Mutex::new(Vec::new()));
+
+    let (c, f) = (Arc::clone(&clock), Arc::clone(&fired));
+    clock.schedule(
+        ms(10),
+        Box::new(move || {
+            record(&c, &f, ms(15));
+            record(&c, &f, ms(50));
+        }),
+    );
+
+    clock.advance(ms(20));
+    assert_eq!(*fired.lock().unwrap(), vec![ms(15)]);
+    assert_eq!(clock.pending(), 1);
+
+    // an alarm already due waits for the next advance, even if it's by zero.
+    record(&clock, &fired, ms(5));
+    assert_eq!(fired.lock().unwrap().len(), 1);
+
+    clock.advance(ms(0));
+    assert_eq!(*fired.lock().unwrap(), vec![ms(15), ms(20)]);
+}
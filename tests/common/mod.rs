@@ -0,0 +1,98 @@
+#![allow(dead_code)]
+
+use futures::task::noop_waker;
+use futures_rate::GateKeeper;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+pub fn poll<F: Future + ?Sized>(fut: Pin<&mut F>) -> Poll<F::Output> {
+    let waker = noop_waker();
+    fut.poll(&mut Context::from_waker(&waker))
+}
+
+/// The number of futures admitted by `try_issue` out of `n`, all of which are done right away.
+pub fn admitted(gatekeeper: &GateKeeper, n: usize) -> usize {
+    (0..n)
+        .filter(|_| gatekeeper.try_issue(async {}).is_ok())
+        .count()
+}
+
+/// Poll the futures round after round till none of them makes progress any more, and return the
+/// indices of the futures resolved, along with their outputs, in the order they were resolved.
+pub fn drive<F: Future + ?Sized>(futs: &mut [Option<Pin<Box<F>>>]) -> Vec<(usize, F::Output)> {
+    let mut done = Vec::new();
+
+    loop {
+        let mut progress = false;
+
+        for (i, slot) in futs.iter_mut().enumerate() {
+            if let Some(fut) = slot.as_mut() {
+                if let Poll::Ready(out) = poll(fut.as_mut()) {
+                    done.push((i, out));
+                    slot.take();
+                    progress = true;
+                }
+            }
+        }
+
+        if !progress {
+            return done;
+        }
+    }
+}
+
+pub fn pinned<F: Future>(futs: impl IntoIterator<Item = F>) -> Vec<Option<Pin<Box<F>>>> {
+    futs.into_iter().map(|f| Some(Box::pin(f))).collect()
+}
+
+/// A future of any kind resolving to a label, such that the futures issued in different ways can
+/// be driven together.
+pub type Labeled = Pin<Box<dyn Future<Output = usize>>>;
+
+pub fn labeled(fut: impl Future<Output = usize> + 'static) -> Option<Labeled> {
+    Some(Box::pin(fut))
+}
+
+/// Poll each of the futures once in the given order, such that they get in line in that order.
+pub fn line_up<F: Future + ?Sized>(futs: &mut [Option<Pin<Box<F>>>], order: &[usize]) {
+    for &i in order {
+        let fut = futs[i].as_mut().expect("the future is done already ...");
+        assert!(poll(fut.as_mut()).is_pending());
+    }
+}
+
+/// The labels of the futures in the order they were resolved.
+pub fn labels<T>(done: Vec<(usize, T)>) -> Vec<T> {
+    done.into_iter().map(|(_, out)| out).collect()
+}
+
+/// A future that stays pending till the gate is opened, to hold on to the tokens for a while.
+#[derive(Clone, Default)]
+pub struct Gate(Arc<AtomicBool>);
+
+impl Gate {
+    pub fn open(&self) {
+        self.0.store(true, Ordering::Release);
+    }
+
+    pub fn wait(&self) -> impl Future<Output = ()> {
+        GateWait(Arc::clone(&self.0))
+    }
+}
+
+struct GateWait(Arc<AtomicBool>);
+
+impl Future for GateWait {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<()> {
+        if self.0.load(Ordering::Acquire) {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
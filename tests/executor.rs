@@ -0,0 +1,73 @@
+use futures::executor::ThreadPool;
+use futures::future::{BoxFuture, FutureExt};
+use futures_rate::{Clock, GateKeeper, GateKeeperConfig, QueueDiscipline, SystemClock};
+use std::sync::mpsc;
+use std::time::Duration;
+
+/// Spawn the futures issued by `issue` on a thread pool, and return how many of them are done
+/// before the line stalls for a few seconds.
+fn completed<F>(count: usize, issue: F) -> usize
+where
+    F: Fn(usize, BoxFuture<'static, ()>) -> BoxFuture<'static, ()>,
+{
+    let pool = ThreadPool::new().unwrap();
+    let (tx, rx) = mpsc::channel();
+
+    for i in 0..count {
+        let tx = tx.clone();
+        pool.spawn_ok(issue(i, async move { tx.send(()).unwrap_or(()) }.boxed()));
+    }
+
+    (0..count)
+        .take_while(|_| rx.recv_timeout(Duration::from_secs(5)).is_ok())
+        .count()
+}
+
+fn capped_rate() -> GateKeeper {
+    GateKeeper::with_capped_rate(3, 50, Duration::from_millis(10))
+}
+
+#[test]
+fn strict_line_keeps_moving_on_a_capped_rate() {
+    for discipline in [QueueDiscipline::Fifo, QueueDiscipline::Lifo] {
+        let mut gatekeeper = capped_rate();
+        gatekeeper.set_queue_discipline(discipline);
+
+        let done = completed(300, |_, fut| gatekeeper.issue(fut).unwrap().boxed());
+        assert_eq!(done, 300);
+        assert_eq!(gatekeeper.waiting(), 0);
+    }
+}
+
+#[test]
+fn ranked_line_keeps_moving_on_a_capped_rate() {
+    let gatekeeper = capped_rate();
+    let done = completed(300, |i, fut| {
+        gatekeeper.issue_with_priority(i % 3, fut).unwrap().boxed()
+    });
+    assert_eq!(done, 300);
+
+    let gatekeeper = capped_rate();
+    let done = completed(300, |i, fut| {
+        gatekeeper.issue_for_tenant(i % 3, 1, fut).unwrap().boxed()
+    });
+    assert_eq!(done, 300);
+}
+
+#[test]
+fn timer_outlives_a_panicking_alarm() {
+    let clock = SystemClock::new();
+    let (tx, rx) = mpsc::channel();
+
+    clock.schedule(clock.now(), Box::new(|| panic!("a faulty alarm")));
+    clock.schedule(
+        clock.now() + Duration::from_millis(10),
+        Box::new(move || tx.send(()).unwrap()),
+    );
+    assert!(rx.recv_timeout(Duration::from_secs(5)).is_ok());
+
+    // and the gatekeepers still have their tokens refilled on time.
+    let gatekeeper = capped_rate();
+    let done = completed(300, |_, fut| gatekeeper.issue(fut).unwrap().boxed());
+    assert_eq!(done, 300);
+}
@@ -0,0 +1,224 @@
+mod common;
+
+use common::{drive, line_up, pinned, poll, Gate};
+use futures_rate::{
+    GateKeeper, GateKeeperConfig, InterruptedReason, MockClock, OverflowPolicy, QueueDiscipline,
+    RatioType, SpinPolicy, TokenPolicy,
+};
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::{Duration, Instant};
+
+fn keeper(ratio: RatioType) -> (GateKeeper, Arc<MockClock>) {
+    let clock = Arc::new(MockClock::new());
+    (GateKeeper::with_clock(ratio, clock.clone()), clock)
+}
+
+fn secs(secs: u64) -> Duration {
+    Duration::from_secs(secs)
+}
+
+#[test]
+fn cancel_takes_the_future_out_of_line() {
+    let (gatekeeper, _) = keeper(RatioType::Static(1));
+
+    let holder = gatekeeper.try_issue(async {}).ok().unwrap();
+    let (fut, controller) = gatekeeper.issue_interruptable(async {}).unwrap();
+    let mut fut = Box::pin(fut);
+    assert!(poll(fut.as_mut()).is_pending());
+    assert_eq!(gatekeeper.waiting(), 1);
+
+    controller.cancel();
+    assert_eq!(
+        poll(fut.as_mut()),
+        Poll::Ready(Err(InterruptedReason::Cancelled))
+    );
+    assert_eq!(gatekeeper.waiting(), 0);
+
+    drop(holder);
+}
+
+#[test]
+fn timeout_fires_once_the_clock_gets_there() {
+    let (gatekeeper, clock) = keeper(RatioType::Static(1));
+
+    let holder = gatekeeper.try_issue(async {}).ok().unwrap();
+    let mut fut = Box::pin(gatekeeper.issue_with_timeout(secs(5), async {}).unwrap());
+    assert!(poll(fut.as_mut()).is_pending());
+
+    clock.advance(secs(5) - Duration::from_millis(1));
+    assert!(poll(fut.as_mut()).is_pending());
+
+    clock.advance(Duration::from_millis(1));
+    assert_eq!(
+        poll(fut.as_mut()),
+        Poll::Ready(Err(InterruptedReason::TimedOut))
+    );
+    assert_eq!(gatekeeper.waiting(), 0);
+
+    // the token held all along is still good for the next one.
+    drop(holder);
+    let mut next = Box::pin(gatekeeper.issue_with_timeout(secs(5), async {}).unwrap());
+    assert_eq!(poll(next.as_mut()), Poll::Ready(Ok(())));
+}
+
+#[test]
+fn timeout_leaves_a_future_admitted_in_time_alone() {
+    let (gatekeeper, clock) = keeper(RatioType::Static(1));
+    let gate = Gate::default();
+
+    let mut fut = Box::pin(gatekeeper.issue_with_timeout(secs(5), gate.wait()).unwrap());
+    assert!(poll(fut.as_mut()).is_pending());
+
+    clock.advance(secs(10));
+    gate.open();
+    assert_eq!(poll(fut.as_mut()), Poll::Ready(Ok(())));
+}
+
+#[test]
+fn deadline_rejects_the_futures_admitted_too_late() {
+    let (gatekeeper, clock) = keeper(RatioType::Static(1));
+
+    let holder = gatekeeper.try_issue(async {}).ok().unwrap();
+    let deadline = Instant::now() + secs(1);
+    let mut futs =
+        pinned((0..2).map(|_| gatekeeper.issue_with_deadline(deadline, async {}).unwrap()));
+    line_up(&mut futs, &[0, 1]);
+
+    clock.advance(secs(2));
+    drop(holder);
+
+    let done = drive(&mut futs);
+    assert_eq!(
+        done,
+        vec![
+            (0, Err(InterruptedReason::DeadlineExceeded)),
+            (1, Err(InterruptedReason::DeadlineExceeded))
+        ]
+    );
+
+    // and the token goes to the ones still in time.
+    let mut fut = Box::pin(
+        gatekeeper
+            .issue_with_deadline(Instant::now() + secs(1), async {})
+            .unwrap(),
+    );
+    assert_eq!(poll(fut.as_mut()), Poll::Ready(Ok(())));
+}
+
+#[test]
+fn deadline_passes_the_token_over_an_expired_future() {
+    let (mut gatekeeper, clock) = keeper(RatioType::Static(1));
+    gatekeeper.set_queue_discipline(QueueDiscipline::Fifo);
+
+    let holder = gatekeeper.try_issue(async {}).ok().unwrap();
+    let mut expired = Box::pin(
+        gatekeeper
+            .issue_with_deadline(Instant::now() + secs(1), async {})
+            .unwrap(),
+    );
+    let mut next = Box::pin(gatekeeper.issue(async {}).unwrap());
+    assert!(poll(expired.as_mut()).is_pending());
+    assert!(poll(next.as_mut()).is_pending());
+
+    // the token goes straight to the next one, not held by the expired one till it's polled.
+    clock.advance(secs(2));
+    drop(holder);
+    assert_eq!(poll(next.as_mut()), Poll::Ready(()));
+
+    assert_eq!(
+        poll(expired.as_mut()),
+        Poll::Ready(Err(InterruptedReason::DeadlineExceeded))
+    );
+    assert_eq!(gatekeeper.waiting(), 0);
+}
+
+#[test]
+fn max_waiters_rejects_the_overflow() {
+    let (mut gatekeeper, _) = keeper(RatioType::Static(1));
+    gatekeeper.set_max_waiters(2, OverflowPolicy::Reject);
+
+    let holder = gatekeeper.try_issue(async {}).ok().unwrap();
+    let waiting: Vec<_> = (0..2)
+        .map(|_| gatekeeper.issue(async {}).unwrap())
+        .collect();
+
+    // the places are held upon issuance, for the plain futures can't be turned away later on.
+    assert!(gatekeeper.issue(async {}).is_none());
+
+    let mut fut = Box::pin(gatekeeper.issue_with_timeout(secs(5), async {}).unwrap());
+    assert_eq!(
+        poll(fut.as_mut()),
+        Poll::Ready(Err(InterruptedReason::QueueFull))
+    );
+
+    drop(waiting);
+    assert!(gatekeeper.issue(async {}).is_some());
+    drop(holder);
+}
+
+#[test]
+fn max_waiters_evicts_the_oldest_to_make_room() {
+    let (mut gatekeeper, _) = keeper(RatioType::Static(1));
+    gatekeeper.set_max_waiters(2, OverflowPolicy::EvictOldest);
+
+    let holder = gatekeeper.try_issue(async {}).ok().unwrap();
+    let mut futs =
+        pinned((0..3).map(|_| gatekeeper.issue_with_timeout(secs(5), async {}).unwrap()));
+    line_up(&mut futs, &[0, 1, 2]);
+
+    assert_eq!(gatekeeper.waiting(), 2);
+    assert_eq!(
+        drive(&mut futs),
+        vec![(0, Err(InterruptedReason::QueueFull))]
+    );
+
+    drop(holder);
+    assert_eq!(drive(&mut futs), vec![(1, Ok(())), (2, Ok(()))]);
+}
+
+#[test]
+fn max_waiters_leaves_the_free_tokens_alone() {
+    let (mut gatekeeper, _) = keeper(RatioType::Static(100));
+    gatekeeper.set_max_waiters(2, OverflowPolicy::Reject);
+
+    // no one has to wait, so the bound of the line doesn't matter.
+    let mut futs = pinned((0..10).map(|i| gatekeeper.issue(async move { i }).unwrap()));
+    assert_eq!(gatekeeper.waiting(), 0);
+    assert_eq!(drive(&mut futs).len(), 10);
+
+    // with no room in line at all, the futures are only turned down once the tokens run out.
+    let (mut gatekeeper, _) = keeper(RatioType::Static(2));
+    gatekeeper.set_max_waiters(0, OverflowPolicy::Reject);
+
+    let mut held: Vec<_> = (0..3).filter_map(|_| gatekeeper.issue(async {})).collect();
+    assert_eq!(held.len(), 2);
+
+    held.pop();
+    assert!(gatekeeper.issue(async {}).is_some());
+}
+
+#[test]
+fn cooperative_backoff_waits_on_the_clock() {
+    let (mut gatekeeper, clock) = keeper(RatioType::Static(1));
+    gatekeeper.set_token_policy(TokenPolicy::Cooperative);
+    gatekeeper.set_spin_policy(SpinPolicy::InplaceWait);
+
+    let gate = Gate::default();
+    let mut fut = Box::pin(gatekeeper.issue(gate.wait()).unwrap());
+
+    // the future keeps coming back pending, till it's held off for a while.
+    (0..4).for_each(|_| assert!(poll(fut.as_mut()).is_pending()));
+    assert_eq!(clock.pending(), 0);
+
+    // a single alarm is set for the backoff, however many times it's polled in between.
+    (0..3).for_each(|_| assert!(poll(fut.as_mut()).is_pending()));
+    assert_eq!(clock.pending(), 1);
+
+    gate.open();
+    assert!(poll(fut.as_mut()).is_pending());
+
+    clock.advance(Duration::from_millis(1));
+    assert_eq!(clock.pending(), 0);
+    assert_eq!(poll(fut.as_mut()), Poll::Ready(()));
+}
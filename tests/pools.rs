@@ -0,0 +1,100 @@
+mod common;
+
+use common::{admitted, poll};
+use futures_rate::{GateKeeper, KeyedGateKeeper, MockClock, RatioType};
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::Duration;
+
+fn ms(millis: u64) -> Duration {
+    Duration::from_millis(millis)
+}
+
+#[test]
+fn keys_are_limited_apart() {
+    let clock = Arc::new(MockClock::new());
+    let keyed = KeyedGateKeeper::with_clock(RatioType::FixedRate(2, ms(100)), clock.clone());
+
+    let admitted = |key: &'static str| {
+        (0..5)
+            .filter(|_| keyed.try_issue(key, async {}).is_ok())
+            .count()
+    };
+
+    assert_eq!(admitted("a"), 2);
+    assert_eq!(admitted("b"), 2);
+    assert_eq!(admitted("a"), 0);
+
+    clock.advance(ms(100));
+    assert_eq!(admitted("a"), 2);
+    assert_eq!(keyed.len(), 2);
+}
+
+#[test]
+fn idle_keys_are_evicted() {
+    let clock = Arc::new(MockClock::new());
+    let mut keyed = KeyedGateKeeper::with_clock(RatioType::Static(1), clock.clone());
+    keyed.set_idle_timeout(Duration::from_secs(10));
+
+    assert!(keyed.try_issue("a", async {}).is_ok());
+    assert!(keyed.try_issue("b", async {}).is_ok());
+    let holder = keyed.try_issue("c", async {}).ok().unwrap();
+    assert_eq!(keyed.len(), 3);
+
+    clock.advance(Duration::from_secs(5));
+    assert_eq!(keyed.evict_idle(), 0);
+
+    // the key still holding a token isn't idle, however long ago it's been issued.
+    clock.advance(Duration::from_secs(6));
+    assert_eq!(keyed.evict_idle(), 2);
+    assert_eq!(keyed.len(), 1);
+
+    drop(holder);
+    clock.advance(Duration::from_secs(11));
+    assert_eq!(keyed.evict_idle(), 1);
+    assert!(keyed.is_empty());
+}
+
+#[test]
+fn child_is_capped_by_its_parent() {
+    let clock = Arc::new(MockClock::new());
+    let parent = GateKeeper::with_clock(RatioType::Static(2), clock);
+    let child = parent.child(RatioType::Static(5));
+
+    let held: Vec<_> = (0..5)
+        .filter_map(|_| child.try_issue(async {}).ok())
+        .collect();
+    assert_eq!(held.len(), 2);
+
+    // the tokens taken by the child are gone from the parent as well.
+    assert_eq!(admitted(&parent, 1), 0);
+
+    drop(held);
+    assert_eq!(admitted(&parent, 2), 2);
+}
+
+#[test]
+fn child_follows_the_rate_of_its_parent() {
+    let clock = Arc::new(MockClock::new());
+    let parent = GateKeeper::with_clock(RatioType::FixedRate(3, ms(100)), clock.clone());
+    let child = parent.child(RatioType::Static(10));
+
+    assert_eq!(admitted(&child, 10), 3);
+
+    clock.advance(ms(100));
+    assert_eq!(admitted(&child, 10), 3);
+}
+
+#[test]
+fn child_waits_for_the_tokens_of_its_parent() {
+    let clock = Arc::new(MockClock::new());
+    let parent = GateKeeper::with_clock(RatioType::Static(1), clock);
+    let child = parent.child(RatioType::Static(5));
+
+    let holder = parent.try_issue(async {}).ok().unwrap();
+    let mut fut = Box::pin(child.issue(async { 1 }).unwrap());
+    assert!(poll(fut.as_mut()).is_pending());
+
+    drop(holder);
+    assert_eq!(poll(fut.as_mut()), Poll::Ready(1));
+}
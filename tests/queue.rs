@@ -0,0 +1,291 @@
+mod common;
+
+use common::{admitted, drive, labeled, labels, line_up, pinned, poll, Gate};
+use futures::task::{waker, ArcWake};
+use futures_rate::{
+    GateKeeper, GateKeeperConfig, InterruptedReason, MockClock, QueueDiscipline, RatioType,
+};
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+fn keeper(ratio: RatioType, discipline: QueueDiscipline) -> (GateKeeper, Arc<MockClock>) {
+    let clock = Arc::new(MockClock::new());
+    let mut gatekeeper = GateKeeper::with_clock(ratio, clock.clone());
+    gatekeeper.set_queue_discipline(discipline);
+
+    (gatekeeper, clock)
+}
+
+fn ms(millis: u64) -> Duration {
+    Duration::from_millis(millis)
+}
+
+/// Line up 5 futures in the reversed order of their labels behind the only token, then free the
+/// token and return the labels in the order the futures are served.
+fn served(gatekeeper: &GateKeeper, clock: &MockClock, wait: Duration) -> Vec<usize> {
+    let holder = gatekeeper.try_issue(async {}).ok().unwrap();
+    let mut futs = pinned((0..5).map(|i| gatekeeper.issue(async move { i }).unwrap()));
+
+    line_up(&mut futs, &[4, 3, 2, 1, 0]);
+    assert_eq!(gatekeeper.waiting(), 5);
+
+    clock.advance(wait);
+    drop(holder);
+
+    labels(drive(&mut futs))
+}
+
+#[test]
+fn a_claim_keeps_the_small_futures_from_starving_the_large_one() {
+    let (gatekeeper, _) = keeper(RatioType::Static(4), QueueDiscipline::Relaxed);
+
+    let held: Vec<_> = (0..3)
+        .filter_map(|_| gatekeeper.try_issue(async {}).ok())
+        .collect();
+
+    let mut futs = vec![
+        labeled(gatekeeper.issue(async { 1 }).unwrap()),
+        labeled(gatekeeper.issue_weighted(4, async { 4 }).unwrap()),
+    ];
+    line_up(&mut futs, &[1, 0]);
+
+    // the token left is set aside for the large one.
+    assert_eq!(admitted(&gatekeeper, 1), 0);
+
+    drop(held);
+    assert_eq!(labels(drive(&mut futs)), vec![4, 1]);
+}
+
+#[test]
+fn a_claim_out_of_reach_after_set_ratio_is_dropped() {
+    let (mut gatekeeper, _) = keeper(RatioType::Static(4), QueueDiscipline::Relaxed);
+
+    let holder = gatekeeper.try_issue(async {}).ok().unwrap();
+    let mut large = Box::pin(gatekeeper.issue_weighted(4, async {}).unwrap());
+    assert!(poll(large.as_mut()).is_pending());
+    assert_eq!(admitted(&gatekeeper, 1), 0);
+
+    // the large one can't ever be admitted now, the rest shan't wait for it.
+    gatekeeper.set_ratio(RatioType::Static(2));
+    drop(holder);
+
+    let mut small = pinned((0..2).map(|i| gatekeeper.issue(async move { i }).unwrap()));
+    assert_eq!(labels(drive(&mut small)), vec![0, 1]);
+    assert_eq!(admitted(&gatekeeper, 2), 2);
+    assert!(poll(large.as_mut()).is_pending());
+}
+
+#[test]
+fn fifo_serves_the_line_in_the_order_of_arrival() {
+    let (gatekeeper, clock) = keeper(RatioType::Static(1), QueueDiscipline::Fifo);
+
+    let holder = gatekeeper.try_issue(async {}).ok().unwrap();
+    let mut waiting = Box::pin(gatekeeper.issue(async {}).unwrap());
+    assert!(poll(waiting.as_mut()).is_pending());
+
+    // no one can get ahead of the line, even with the token freed up.
+    drop(holder);
+    assert_eq!(admitted(&gatekeeper, 1), 0);
+    assert_eq!(poll(waiting.as_mut()), Poll::Ready(()));
+    drop(waiting);
+
+    assert_eq!(served(&gatekeeper, &clock, ms(0)), vec![4, 3, 2, 1, 0]);
+    assert_eq!(gatekeeper.waiting(), 0);
+}
+
+#[test]
+fn a_dropped_future_leaves_the_line_and_passes_on_its_token() {
+    let (gatekeeper, _) = keeper(RatioType::Static(1), QueueDiscipline::Fifo);
+
+    let holder = gatekeeper.try_issue(async {}).ok().unwrap();
+    let mut futs = pinned((0..3).map(|i| gatekeeper.issue(async move { i }).unwrap()));
+    line_up(&mut futs, &[0, 1, 2]);
+
+    futs[0].take();
+    assert_eq!(gatekeeper.waiting(), 2);
+
+    // the token is handed to the next one, who's dropped before it could use it.
+    drop(holder);
+    futs[1].take();
+
+    assert_eq!(labels(drive(&mut futs)), vec![2]);
+    assert_eq!(gatekeeper.waiting(), 0);
+}
+
+/// A waker counting how many times it's been woken.
+#[derive(Default)]
+struct Counter(AtomicUsize);
+
+impl ArcWake for Counter {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn a_future_polled_again_keeps_one_place_and_one_waker() {
+    let (gatekeeper, _) = keeper(RatioType::Static(1), QueueDiscipline::Relaxed);
+
+    let holder = gatekeeper.try_issue(async {}).ok().unwrap();
+    let mut fut = Box::pin(gatekeeper.issue(async {}).unwrap());
+
+    let counters: Vec<_> = (0..3).map(|_| Arc::new(Counter::default())).collect();
+    for counter in counters.iter() {
+        let waker = waker(Arc::clone(counter));
+        let res = fut.as_mut().poll(&mut Context::from_waker(&waker));
+        assert!(res.is_pending());
+    }
+
+    assert_eq!(gatekeeper.waiting(), 1);
+
+    // only the waker of the latest poll is woken, and only once.
+    drop(holder);
+    let woken: Vec<_> = counters
+        .iter()
+        .map(|c| c.0.load(Ordering::SeqCst))
+        .collect();
+    assert_eq!(woken, vec![0, 0, 1]);
+    assert_eq!(poll(fut.as_mut()), Poll::Ready(()));
+}
+
+#[test]
+fn priorities_are_served_first_with_no_barging() {
+    let (gatekeeper, _) = keeper(RatioType::Static(1), QueueDiscipline::Relaxed);
+
+    let holder = gatekeeper.try_issue(async {}).ok().unwrap();
+    let mut futs = vec![
+        labeled(gatekeeper.issue(async { 0 }).unwrap()),
+        labeled(gatekeeper.issue_with_priority(5, async { 5 }).unwrap()),
+        labeled(gatekeeper.issue_with_priority(9, async { 9 }).unwrap()),
+        labeled(gatekeeper.issue_with_priority(5, async { 6 }).unwrap()),
+    ];
+    line_up(&mut futs, &[0, 1, 2, 3]);
+
+    // even when relaxed, the token goes to the line rather than a newly arriving future.
+    drop(holder);
+    assert_eq!(admitted(&gatekeeper, 1), 0);
+
+    assert_eq!(labels(drive(&mut futs)), vec![9, 5, 6, 0]);
+}
+
+#[test]
+fn aging_lifts_the_long_waiting_futures() {
+    let (mut gatekeeper, clock) = keeper(RatioType::Static(1), QueueDiscipline::Fifo);
+    gatekeeper.set_priority_aging(Some(ms(100)));
+
+    let holder = gatekeeper.try_issue(async {}).ok().unwrap();
+    let mut futs = vec![
+        labeled(gatekeeper.issue(async { 0 }).unwrap()),
+        labeled(gatekeeper.issue_with_priority(2, async { 2 }).unwrap()),
+    ];
+    line_up(&mut futs, &[0]);
+
+    // 10 priorities up by the time the other one arrives.
+    clock.advance(Duration::from_secs(1));
+    line_up(&mut futs, &[1]);
+
+    drop(holder);
+    assert_eq!(labels(drive(&mut futs)), vec![0, 2]);
+}
+
+#[test]
+fn tenants_take_turns_by_their_weights() {
+    let (gatekeeper, _) = keeper(RatioType::Static(1), QueueDiscipline::Relaxed);
+
+    let line = |heavy: usize| {
+        let holder = gatekeeper.try_issue(async {}).ok().unwrap();
+        let mut futs = Vec::new();
+
+        for i in 0..4 {
+            futs.push(labeled(
+                gatekeeper
+                    .issue_for_tenant(1, 1, async move { 10 + i })
+                    .unwrap(),
+            ));
+        }
+
+        for i in 0..2 {
+            futs.push(labeled(
+                gatekeeper
+                    .issue_for_tenant(2, heavy, async move { 20 + i })
+                    .unwrap(),
+            ));
+        }
+
+        line_up(&mut futs, &[0, 1, 2, 3, 4, 5]);
+
+        drop(holder);
+        assert_eq!(admitted(&gatekeeper, 1), 0);
+
+        labels(drive(&mut futs))
+    };
+
+    assert_eq!(line(1), vec![10, 20, 11, 21, 12, 13]);
+    assert_eq!(line(2), vec![20, 10, 21, 11, 12, 13]);
+}
+
+#[test]
+fn lifo_serves_the_latest_arrival_first() {
+    let (gatekeeper, clock) = keeper(RatioType::Static(1), QueueDiscipline::Lifo);
+    assert_eq!(served(&gatekeeper, &clock, ms(0)), vec![0, 1, 2, 3, 4]);
+}
+
+#[test]
+fn adaptive_lifo_turns_to_lifo_once_congested() {
+    let congested = QueueDiscipline::AdaptiveLifo {
+        congested: Duration::from_secs(1),
+    };
+    let (gatekeeper, clock) = keeper(RatioType::Static(1), congested);
+
+    assert_eq!(served(&gatekeeper, &clock, ms(500)), vec![4, 3, 2, 1, 0]);
+    assert_eq!(served(&gatekeeper, &clock, ms(1500)), vec![0, 1, 2, 3, 4]);
+}
+
+#[test]
+fn codel_sheds_a_standing_line() {
+    let codel = QueueDiscipline::CoDel {
+        target: ms(100),
+        interval: Duration::from_secs(1),
+    };
+    let (gatekeeper, clock) = keeper(RatioType::Static(1), codel);
+    let gate = Gate::default();
+
+    let holder = gatekeeper.try_issue(async {}).ok().unwrap();
+    let mut futs = pinned((0..4).map(|_| {
+        gatekeeper
+            .issue_with_timeout(Duration::from_secs(3600), gate.wait())
+            .unwrap()
+    }));
+    line_up(&mut futs, &[0, 1, 2, 3]);
+
+    // the line starts to stand, while it's not been standing for the interval yet.
+    clock.advance(ms(200));
+    drop(holder);
+
+    let first = futs[0].as_mut().unwrap();
+    assert!(poll(first.as_mut()).is_pending());
+    assert_eq!(gatekeeper.waiting(), 3);
+
+    // it's been standing for the interval when the token comes back, so is the rest shed.
+    clock.advance(ms(1100));
+    gate.open();
+
+    let done = drive(&mut futs);
+    assert_eq!(done[0], (0, Ok(())));
+    assert!(done[1..]
+        .iter()
+        .all(|(_, out)| *out == Err(InterruptedReason::Overloaded)));
+    assert_eq!(done.len(), 4);
+    assert_eq!(gatekeeper.waiting(), 0);
+
+    // and the line is cleared up for the new arrivals.
+    let mut fresh = Box::pin(
+        gatekeeper
+            .issue_with_timeout(Duration::from_secs(3600), async {})
+            .unwrap(),
+    );
+    assert_eq!(poll(fresh.as_mut()), Poll::Ready(Ok(())));
+}
@@ -0,0 +1,373 @@
+mod common;
+
+use common::{admitted, poll};
+use futures_rate::{GateKeeper, GateKeeperConfig, InterruptedReason, MockClock, RatioType};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+fn keeper(ratio: RatioType) -> (GateKeeper, Arc<MockClock>) {
+    let clock = Arc::new(MockClock::new());
+    (GateKeeper::with_clock(ratio, clock.clone()), clock)
+}
+
+fn ms(millis: u64) -> Duration {
+    Duration::from_millis(millis)
+}
+
+/// Pending on the first poll, done on the next one, such that it's in flight in between.
+struct Twice(bool);
+
+impl Future for Twice {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<()> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+
+        self.0 = true;
+        Poll::Pending
+    }
+}
+
+#[test]
+fn token_bucket_refills_continuously() {
+    let (gatekeeper, clock) = keeper(RatioType::TokenBucket {
+        capacity: 5,
+        refill: 10,
+        per: Duration::from_secs(1),
+    });
+
+    assert_eq!(admitted(&gatekeeper, 10), 5);
+
+    clock.advance(ms(250));
+    assert_eq!(admitted(&gatekeeper, 10), 2);
+
+    // never more than the capacity, however long it's been idle.
+    clock.advance(Duration::from_secs(10));
+    assert_eq!(admitted(&gatekeeper, 10), 5);
+}
+
+#[test]
+fn token_bucket_only_refills_on_schedule_while_someone_waits() {
+    let (gatekeeper, clock) = keeper(RatioType::TokenBucket {
+        capacity: 1,
+        refill: 10,
+        per: Duration::from_secs(1),
+    });
+
+    // an idle bucket is refilled by whoever asks for a token next.
+    assert_eq!(clock.pending(), 0);
+    assert_eq!(admitted(&gatekeeper, 5), 1);
+    assert_eq!(clock.pending(), 0);
+
+    let mut fut = Box::pin(gatekeeper.issue(async {}).unwrap());
+    assert!(poll(fut.as_mut()).is_pending());
+    assert_eq!(clock.pending(), 1);
+
+    clock.advance(ms(100));
+    assert_eq!(poll(fut.as_mut()), Poll::Ready(()));
+
+    // the last refill finds no one waiting, and stops there.
+    clock.advance(ms(100));
+    assert_eq!(clock.pending(), 0);
+    assert_eq!(admitted(&gatekeeper, 5), 1);
+}
+
+#[test]
+fn gcra_admits_the_burst_then_the_rate() {
+    let (gatekeeper, clock) = keeper(RatioType::Gcra {
+        rate: 10,
+        per: Duration::from_secs(1),
+        burst: 3,
+    });
+
+    assert_eq!(admitted(&gatekeeper, 10), 3);
+
+    clock.advance(ms(99));
+    assert_eq!(admitted(&gatekeeper, 10), 0);
+
+    clock.advance(ms(1));
+    assert_eq!(admitted(&gatekeeper, 10), 1);
+}
+
+#[test]
+fn gcra_pays_the_cost_forward() {
+    let (gatekeeper, clock) = keeper(RatioType::Gcra {
+        rate: 1,
+        per: Duration::from_secs(1),
+        burst: 1,
+    });
+
+    assert!(gatekeeper.try_issue_weighted(5, async {}).is_ok());
+
+    clock.advance(Duration::from_secs(4));
+    assert_eq!(admitted(&gatekeeper, 1), 0);
+
+    clock.advance(Duration::from_secs(1));
+    assert_eq!(admitted(&gatekeeper, 1), 1);
+
+    // a cost that can't be paid forward within the time the clock can count is turned down.
+    clock.advance(Duration::from_secs(1));
+    assert!(gatekeeper
+        .try_issue_weighted(usize::MAX / 2, async {})
+        .is_err());
+    assert!(gatekeeper.issue_weighted(usize::MAX, async {}).is_none());
+    assert_eq!(admitted(&gatekeeper, 1), 1);
+}
+
+#[test]
+fn sliding_window_log_counts_the_last_window() {
+    let (gatekeeper, clock) = keeper(RatioType::SlidingWindowLog(5, Duration::from_secs(1)));
+
+    assert_eq!(admitted(&gatekeeper, 10), 5);
+
+    clock.advance(ms(500));
+    assert_eq!(admitted(&gatekeeper, 10), 0);
+
+    clock.advance(ms(500));
+    assert_eq!(admitted(&gatekeeper, 10), 5);
+}
+
+#[test]
+fn sliding_window_counter_weighs_the_previous_window() {
+    let (gatekeeper, clock) = keeper(RatioType::SlidingWindowCounter(10, Duration::from_secs(1)));
+
+    assert_eq!(admitted(&gatekeeper, 20), 10);
+
+    // half of the previous window still counts.
+    clock.advance(ms(1500));
+    assert_eq!(admitted(&gatekeeper, 20), 5);
+}
+
+#[test]
+fn pacing_spaces_the_admissions_evenly() {
+    let (gatekeeper, clock) = keeper(RatioType::Pacing {
+        count: 10,
+        per: Duration::from_secs(1),
+        max_delay: None,
+    });
+
+    assert_eq!(admitted(&gatekeeper, 10), 1);
+
+    clock.advance(ms(99));
+    assert_eq!(admitted(&gatekeeper, 10), 0);
+
+    clock.advance(ms(1));
+    assert_eq!(admitted(&gatekeeper, 10), 1);
+}
+
+#[test]
+fn pacing_turns_down_the_futures_past_the_max_delay() {
+    let (gatekeeper, clock) = keeper(RatioType::Pacing {
+        count: 10,
+        per: Duration::from_secs(1),
+        max_delay: Some(ms(150)),
+    });
+
+    assert_eq!(admitted(&gatekeeper, 1), 1);
+
+    // the next admission is 100ms away, which is still in time.
+    let mut first = Box::pin(
+        gatekeeper
+            .issue_with_timeout(Duration::from_secs(5), async {})
+            .unwrap(),
+    );
+    assert!(poll(first.as_mut()).is_pending());
+
+    // 200ms away by the end of the line.
+    let mut second = Box::pin(
+        gatekeeper
+            .issue_with_timeout(Duration::from_secs(5), async {})
+            .unwrap(),
+    );
+    assert_eq!(
+        poll(second.as_mut()),
+        Poll::Ready(Err(InterruptedReason::DelayExceeded))
+    );
+
+    // a plain future can't be turned away once issued, so it's turned down right away.
+    assert!(gatekeeper.issue(async {}).is_none());
+
+    clock.advance(ms(100));
+    assert_eq!(poll(first.as_mut()), Poll::Ready(Ok(())));
+}
+
+#[test]
+fn composite_caps_both_the_flight_and_the_rate() {
+    let (gatekeeper, clock) = keeper(RatioType::Composite {
+        in_flight: 2,
+        count: 3,
+        per: Duration::from_secs(1),
+    });
+
+    let held: Vec<_> = (0..5)
+        .filter_map(|_| gatekeeper.try_issue(async {}).ok())
+        .collect();
+    assert_eq!(held.len(), 2);
+
+    drop(held);
+
+    // room in flight, yet only one left by the rate.
+    assert_eq!(admitted(&gatekeeper, 5), 1);
+
+    clock.advance(Duration::from_secs(1));
+    assert_eq!(admitted(&gatekeeper, 5), 3);
+}
+
+#[test]
+fn aimd_backs_off_on_failures_and_grows_on_successes() {
+    let (gatekeeper, _) = keeper(RatioType::Aimd {
+        initial: 4,
+        min: 1,
+        max: 8,
+        backoff: 0.5,
+    });
+
+    let mut failed = Box::pin(
+        gatekeeper
+            .issue_fallible(async { Err::<(), ()>(()) })
+            .unwrap(),
+    );
+    assert_eq!(poll(failed.as_mut()), Poll::Ready(Err(())));
+
+    let held: Vec<_> = (0..5)
+        .filter_map(|_| gatekeeper.try_issue(async {}).ok())
+        .collect();
+    assert_eq!(held.len(), 2);
+    drop(held);
+
+    // a success grows the cap only while the cap is in use.
+    let holder = gatekeeper.try_issue(async {}).ok().unwrap();
+    let mut succeeded = Box::pin(
+        gatekeeper
+            .issue_fallible(async { Ok::<(), ()>(()) })
+            .unwrap(),
+    );
+    assert_eq!(poll(succeeded.as_mut()), Poll::Ready(Ok(())));
+
+    drop(holder);
+
+    let held: Vec<_> = (0..5)
+        .filter_map(|_| gatekeeper.try_issue(async {}).ok())
+        .collect();
+    assert_eq!(held.len(), 3);
+}
+
+#[test]
+fn gradient_shrinks_on_latency_and_follows_a_lasting_rise() {
+    let (gatekeeper, clock) = keeper(RatioType::Gradient {
+        initial: 20,
+        min: 2,
+        max: 100,
+    });
+
+    // run as many futures as the cap allows for a round trip of the given time, and return the cap.
+    let round = |rtt: Duration| {
+        let mut futs: Vec<_> = std::iter::from_fn(|| gatekeeper.try_issue(Twice(false)).ok())
+            .take(200)
+            .map(Box::pin)
+            .collect();
+
+        futs.iter_mut()
+            .for_each(|f| assert!(poll(f.as_mut()).is_pending()));
+        clock.advance(rtt);
+        futs.iter_mut()
+            .for_each(|f| assert!(poll(f.as_mut()).is_ready()));
+
+        futs.len()
+    };
+
+    (0..50).for_each(|_| {
+        round(ms(10));
+    });
+    assert_eq!(round(ms(10)), 100);
+
+    // the futures start to queue up on the other end.
+    round(ms(100));
+    assert!(round(ms(100)) < 10);
+
+    // the latency stays up for good, the cap shan't be pinned at the min.
+    (0..100).for_each(|_| {
+        round(ms(100));
+    });
+    assert_eq!(round(ms(100)), 100);
+}
+
+#[test]
+fn static_caps_the_futures_in_flight() {
+    let (gatekeeper, _) = keeper(RatioType::Static(2));
+
+    let held: Vec<_> = (0..5)
+        .filter_map(|_| gatekeeper.try_issue(async {}).ok())
+        .collect();
+    assert_eq!(held.len(), 2);
+    assert_eq!(admitted(&gatekeeper, 5), 0);
+
+    drop(held);
+    assert_eq!(admitted(&gatekeeper, 5), 5);
+}
+
+#[test]
+#[should_panic(expected = "is_valid")]
+fn set_ratio_turns_down_a_bucket_never_refilled() {
+    let (mut gatekeeper, _) = keeper(RatioType::Static(1));
+    gatekeeper.set_ratio(RatioType::TokenBucket {
+        capacity: 5,
+        refill: 0,
+        per: ms(10),
+    });
+}
+
+#[test]
+#[should_panic(expected = "is_valid")]
+fn with_clock_turns_down_a_bucket_never_refilled() {
+    keeper(RatioType::TokenBucket {
+        capacity: 5,
+        refill: 0,
+        per: ms(10),
+    });
+}
+
+#[test]
+fn fixed_rate_refills_on_the_interval() {
+    let (gatekeeper, clock) = keeper(RatioType::FixedRate(5, ms(100)));
+
+    assert_eq!(admitted(&gatekeeper, 10), 5);
+
+    clock.advance(ms(99));
+    assert_eq!(admitted(&gatekeeper, 10), 0);
+
+    clock.advance(ms(1));
+    assert_eq!(admitted(&gatekeeper, 10), 5);
+}
+
+#[test]
+fn set_ratio_switches_the_flavor() {
+    let (mut gatekeeper, clock) = keeper(RatioType::Static(1));
+    assert_eq!(admitted(&gatekeeper, 5), 5);
+
+    gatekeeper.set_ratio(RatioType::FixedRate(2, ms(100)));
+    assert_eq!(admitted(&gatekeeper, 5), 2);
+
+    clock.advance(ms(100));
+    assert_eq!(admitted(&gatekeeper, 5), 2);
+}
+
+#[test]
+fn set_ratio_starts_the_refills_over_on_a_new_period() {
+    let (mut gatekeeper, clock) = keeper(RatioType::FixedRate(1, Duration::from_secs(3600)));
+    assert_eq!(admitted(&gatekeeper, 5), 1);
+
+    // no waiting for the refill an hour away, the new period applies from now on.
+    gatekeeper.set_ratio(RatioType::FixedRate(1, ms(10)));
+    assert_eq!(admitted(&gatekeeper, 5), 1);
+
+    for _ in 0..3 {
+        clock.advance(ms(10));
+        assert_eq!(admitted(&gatekeeper, 5), 1);
+    }
+}